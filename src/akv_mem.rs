@@ -1,33 +1,115 @@
-use libactionkv::ActionKV;
+use libactionkv::{ActionKV, Durability, Scan};
 
 #[cfg(target_os = "windows")]
-const uSAGE: &str = "
+const USAGE: &str = "
     Usage:
-        akv_mem.exe FILE get KEY
-        akv_mem.exe FILE delete KEY
-        akv_mem.exe FILE insert KEY VALUE
-        akv_mem.exe FILE update KEY VALUE
+        akv_mem.exe STORE get KEY
+        akv_mem.exe STORE delete KEY
+        akv_mem.exe STORE insert KEY VALUE
+        akv_mem.exe STORE update KEY VALUE
+        akv_mem.exe STORE incr KEY [DELTA]
+        akv_mem.exe STORE decr KEY [DELTA]
+        akv_mem.exe STORE scan PREFIX
+        akv_mem.exe STORE range START END
+        akv_mem.exe STORE upgrade
+        akv_mem.exe STORE repair
 ";
 
 #[cfg(not(target_os = "windows"))]
 const USAGE: &str = "
     Usage:
-        akv_mem FILE get KEY
-        akv_mem FILE delete KEY
-        akv_mem FILE insert KEY VALUE
-        akv_mem FILE update KEY VALUE
+        akv_mem STORE get KEY
+        akv_mem STORE delete KEY
+        akv_mem STORE insert KEY VALUE
+        akv_mem STORE update KEY VALUE
+        akv_mem STORE incr KEY [DELTA]
+        akv_mem STORE decr KEY [DELTA]
+        akv_mem STORE scan PREFIX
+        akv_mem STORE range START END
+        akv_mem STORE upgrade
+        akv_mem STORE repair
 ";
 
 fn main() {
     let args: Vec<String> = std::env::args().collect();
-    let fname = args.get(1).expect(&USAGE);
-    let action: &str = args.get(2).expect(&USAGE).as_ref();
-    let key: &str = args.get(3).expect(&USAGE).as_ref();
-    let maybe_value = args.get(4);
-
+    // Checked before the store is opened, as opening it to write can change
+    // it.
+    let arities = match args.get(2).map(String::as_str) {
+        Some("upgrade" | "repair") => 3..=3,
+        Some("get" | "delete" | "scan") => 4..=4,
+        Some("insert" | "update" | "range") => 5..=5,
+        Some("incr" | "decr") => 4..=5,
+        _ => usage(),
+    };
+    if !arities.contains(&args.len()) {
+        usage();
+    }
+    let fname = &args[1];
+    let action: &str = &args[2];
     let path = std::path::Path::new(fname);
-    let mut store = ActionKV::open(path).expect("Unable to open file");
-    store.load().expect("unable to load data");
+
+    if action == "upgrade" {
+        match ActionKV::upgrade(path) {
+            Ok(true) => println!("upgraded {:?} to the current format", fname),
+            Ok(false) => println!("{:?} is already in the current format", fname),
+            Err(err) => {
+                eprintln!("unable to upgrade {:?}: {}", fname, err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let mut options = ActionKV::options();
+    options.durability(Durability::Always);
+
+    if action == "repair" {
+        match options.open_and_repair(path) {
+            Ok((_, 0)) => println!("{:?} has no damaged records", fname),
+            Ok((_, discarded)) => println!("discarded {} bytes from {:?}", discarded, fname),
+            Err(err) => {
+                eprintln!("unable to repair {:?}: {}", fname, err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let key: &str = &args[3];
+    let maybe_value = args.get(4).map(String::as_str);
+    let delta: i64 = match (action, maybe_value) {
+        ("incr" | "decr", Some(delta)) => delta.parse().unwrap_or_else(|_| usage()),
+        _ => 1,
+    };
+
+    // Reads take no lock and leave the log as it is. Writes lock the store
+    // against other writers, so that `incr` and `decr` lose no updates, and
+    // first truncate a torn final record, which they would otherwise be
+    // appended after.
+    let reads_only = matches!(action, "get" | "scan" | "range");
+    let loaded = if reads_only {
+        options
+            .open_read_only(path)
+            .and_then(|mut store| store.load().map(|_| (store, 0)))
+    } else {
+        options.open_and_recover(path)
+    };
+    let (mut store, discarded) = match loaded {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("unable to load {:?}: {}", fname, err);
+            if err.is_corruption() {
+                eprintln!("run `akv_mem STORE repair` to discard the damaged records");
+            }
+            std::process::exit(1);
+        }
+    };
+    if discarded > 0 {
+        eprintln!(
+            "warning: discarded {} bytes of a torn write from {:?}",
+            discarded, fname
+        );
+    }
 
     match action {
         "get" => match store.get(key.as_bytes()).unwrap() {
@@ -36,14 +118,47 @@ fn main() {
         },
         "delete" => store.delete(key.as_bytes()).unwrap(),
         "insert" => {
-            let value: &str = maybe_value.expect("&USAGE").as_ref();
+            let value = &args[4];
             store.insert(key.as_bytes(), value.as_bytes()).unwrap()
         }
         "update" => {
-            let value: &str = maybe_value.expect("&USAGE").as_ref();
+            let value = &args[4];
             store.update(key.as_bytes(), value.as_bytes()).unwrap()
         }
+        "incr" | "decr" => {
+            let count = match action {
+                "incr" => store.incr(key.as_bytes(), delta),
+                _ => store.decr(key.as_bytes(), delta),
+            };
+            match count {
+                Ok(count) => println!("{}", count),
+                Err(err) => {
+                    eprintln!("unable to {} {:?}: {}", action, key, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+        "scan" => print_pairs(store.scan_prefix(key.as_bytes())),
+        "range" => {
+            let end = &args[4];
+            print_pairs(store.range(key.as_bytes()..end.as_bytes()))
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(1);
+}
 
-        _ => eprintln!("{}", &USAGE),
+fn print_pairs(scan: Scan) {
+    for kv in scan {
+        let kv = kv.unwrap();
+        println!(
+            "{:?} {:?}",
+            String::from_utf8_lossy(&kv.key),
+            String::from_utf8_lossy(&kv.value)
+        );
     }
 }
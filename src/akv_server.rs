@@ -0,0 +1,70 @@
+use std::net::TcpListener;
+
+use libactionkv::{http, net, resp, ActionKV, ActionKVHandle, Durability};
+
+#[cfg(target_os = "windows")]
+const USAGE: &str = "
+    Usage:
+        akv_server.exe [--resp | --http] STORE [ADDRESS]
+";
+
+#[cfg(not(target_os = "windows"))]
+const USAGE: &str = "
+    Usage:
+        akv_server [--resp | --http] STORE [ADDRESS]
+";
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:4000";
+const DEFAULT_RESP_ADDRESS: &str = "127.0.0.1:6379";
+const DEFAULT_HTTP_ADDRESS: &str = "127.0.0.1:8080";
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let protocol = match args.first().map(String::as_str) {
+        Some(flag @ ("--resp" | "--http")) => {
+            let flag = flag.to_string();
+            args.remove(0);
+            flag
+        }
+        _ => String::new(),
+    };
+
+    let fname = args.first().expect(USAGE);
+    let default_address = match protocol.as_str() {
+        "--resp" => DEFAULT_RESP_ADDRESS,
+        "--http" => DEFAULT_HTTP_ADDRESS,
+        _ => DEFAULT_ADDRESS,
+    };
+    let address = args.get(1).map(String::as_str).unwrap_or(default_address);
+
+    let path = std::path::Path::new(fname);
+    let (store, discarded) = match ActionKV::options()
+        .durability(Durability::Always)
+        .open_and_recover(path)
+    {
+        Ok(loaded) => loaded,
+        Err(err) => {
+            eprintln!("unable to load {:?}: {}", fname, err);
+            if err.is_corruption() {
+                eprintln!("run `akv_mem STORE repair` to discard the damaged records");
+            }
+            std::process::exit(1);
+        }
+    };
+    if discarded > 0 {
+        eprintln!(
+            "warning: discarded {} bytes of a torn write from {:?}",
+            discarded, fname
+        );
+    }
+
+    let listener = TcpListener::bind(address).expect("unable to bind address");
+    eprintln!("serving {:?} on {}", fname, address);
+    let handle = ActionKVHandle::new(store);
+    let served = match protocol.as_str() {
+        "--resp" => resp::serve(listener, handle),
+        "--http" => http::serve(listener, handle),
+        _ => net::serve(listener, handle),
+    };
+    served.expect("server failed");
+}
@@ -0,0 +1,107 @@
+use crate::{ActionKV, ActionKVError, ByteStr, ByteString, Encoding, Record, RecordKind, Result};
+
+/// A group of puts and deletes that is written to the log as one unit.
+///
+/// The whole batch shares a single checksum, so after a crash `load` sees
+/// either all of its operations or none of them.
+///
+/// ```no_run
+/// # use libactionkv::{ActionKV, WriteBatch};
+/// # let mut store = ActionKV::open(std::path::Path::new("store.akv"))?;
+/// let mut batch = WriteBatch::new();
+/// batch.put(b"account:1", b"90").put(b"account:2", b"110");
+/// batch.delete(b"transfer:pending");
+/// store.write_batch(&batch)?;
+/// # Ok::<(), libactionkv::ActionKVError>(())
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<(RecordKind, ByteString, ByteString)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a write of `value` to `key`.
+    pub fn put(&mut self, key: &ByteStr, value: &ByteStr) -> &mut Self {
+        self.ops
+            .push((RecordKind::Value, key.to_vec(), value.to_vec()));
+        self
+    }
+
+    /// Adds a deletion of `key`.
+    pub fn delete(&mut self, key: &ByteStr) -> &mut Self {
+        self.ops
+            .push((RecordKind::Tombstone, key.to_vec(), ByteString::new()));
+        self
+    }
+
+    /// Number of operations in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.ops.clear();
+    }
+
+    /// Encodes every operation as an ordinary record, returning the
+    /// concatenated records and the offset of each one within them.
+    pub(crate) fn encode(&self, encoding: &Encoding) -> Result<(ByteString, Vec<u64>)> {
+        let mut body = ByteString::new();
+        let mut offsets = Vec::with_capacity(self.ops.len());
+
+        for (kind, key, value) in &self.ops {
+            offsets.push(body.len() as u64);
+            ActionKV::write_record(&mut body, *kind, key, value, None, encoding)?;
+        }
+
+        Ok((body, offsets))
+    }
+
+    pub(crate) fn ops(&self) -> impl Iterator<Item = (RecordKind, &ByteStr)> {
+        self.ops
+            .iter()
+            .map(|(kind, key, _)| (*kind, key.as_slice()))
+    }
+}
+
+/// Decodes the records held in the value of a batch record, pairing each with
+/// its offset in the log. `offset` is where the value starts in the log.
+pub(crate) fn decode(
+    mut body: &ByteStr,
+    offset: u64,
+    encoding: &Encoding,
+) -> Result<Vec<(u64, Record)>> {
+    let len = body.len();
+    let mut records = Vec::new();
+
+    while !body.is_empty() {
+        let position = offset + (len - body.len()) as u64;
+        let record = ActionKV::process_record(&mut body, position, encoding)?
+            .ok_or(ActionKVError::TruncatedRecord { offset: position })?;
+
+        if !matches!(record.kind, RecordKind::Value | RecordKind::Tombstone) {
+            return Err(ActionKVError::BadHeader {
+                offset: position,
+                reason: format!("{:?} record nested in a batch", record.kind),
+            });
+        }
+        records.push((position, record));
+    }
+
+    Ok(records)
+}
+
+/// The length of each record of a batch, given where each starts and where
+/// the last one ends.
+pub(crate) fn record_lens(offsets: &[u64], end: u64) -> impl Iterator<Item = u64> + '_ {
+    let ends = offsets.iter().skip(1).copied().chain([end]);
+    offsets.iter().zip(ends).map(|(start, end)| end - start)
+}
@@ -0,0 +1,169 @@
+//! The marker file that makes installing compacted segments atomic.
+//!
+//! Compaction writes its segments to `.compact` files under fresh ids, above
+//! every segment already in the store, and syncs them. It then writes this
+//! file, listing the ids it installs and those of the segments they
+//! replace, and syncs the directory: from then on the compaction has
+//! happened. The `.compact` files are renamed into place and the replaced
+//! segments removed, by `compact` itself or, after a crash, by the next
+//! open. A `.compact` file with no marker file is left over from a
+//! compaction that never got that far, and is deleted by the next open for
+//! writing, which takes the store's lock.
+//!
+//! Layout: the count and ids (`u32`) of the segments installed, then those of
+//! the segments removed, framed like the index file. Ids say nothing about
+//! the contents of a store, so the file is never encrypted.
+
+use std::{
+    fs,
+    io::{self, Read as _},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
+
+use crate::{
+    hint,
+    index_file::{read_framed, write_framed},
+    segment, ActionKV, Result,
+};
+
+const MAGIC: &[u8; 4] = b"AKVC";
+
+const NAME: &str = "compaction";
+
+const TMP_SUFFIX: &str = ".compact";
+
+/// The segments a compaction swaps in and out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Manifest {
+    pub(crate) installed: Vec<u32>,
+    pub(crate) removed: Vec<u32>,
+}
+
+/// The temporary file that the compacted segment `id` is written to.
+pub(crate) fn tmp_path(dir: &Path, id: u32) -> PathBuf {
+    ActionKV::sibling_path(&segment::path(dir, id), TMP_SUFFIX)
+}
+
+/// Durably writes the marker file for `manifest`, after which the
+/// compaction it describes is finished on open if it is interrupted.
+pub(crate) fn commit(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let mut body = Vec::new();
+    for ids in [&manifest.installed, &manifest.removed] {
+        body.write_u64::<LittleEndian>(ids.len() as u64)?;
+        for id in ids {
+            body.write_u32::<LittleEndian>(*id)?;
+        }
+    }
+
+    let path = dir.join(NAME);
+    let tmp_path = ActionKV::sibling_path(&path, ".tmp");
+    write_framed(&path, &tmp_path, (MAGIC, MAGIC), body, None)?;
+    ActionKV::sync_parent_dir(&path)?;
+    Ok(())
+}
+
+/// Renames the compacted segments of `manifest` into place, removes the
+/// segments they replace along with their hint files and the index file,
+/// which refers to them, and finally the marker file. Steps that were
+/// already taken before an interruption are skipped.
+pub(crate) fn finish(dir: &Path, manifest: &Manifest) -> Result<()> {
+    for id in &manifest.installed {
+        match fs::rename(tmp_path(dir, *id), segment::path(dir, *id)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    ActionKV::sync_parent_dir(&dir.join(NAME))?;
+
+    remove_if_present(&dir.join("index"))?;
+    for id in &manifest.removed {
+        remove_if_present(&segment::path(dir, *id))?;
+        hint::remove(dir, *id)?;
+    }
+    ActionKV::sync_parent_dir(&dir.join(NAME))?;
+
+    let path = dir.join(NAME);
+    remove_if_present(&path)?;
+    ActionKV::sync_parent_dir(&path)?;
+    Ok(())
+}
+
+/// Finishes a compaction that was interrupted after it was committed, and
+/// deletes the `.compact` files of one that was interrupted before.
+pub(crate) fn recover(dir: &Path) -> Result<()> {
+    if let Some(manifest) = read(dir)? {
+        finish(dir, &manifest)?;
+    }
+
+    let mut stale = false;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if name.to_str().is_some_and(|name| name.ends_with(TMP_SUFFIX)) {
+            remove_if_present(&entry.path())?;
+            stale = true;
+        }
+    }
+    if stale {
+        ActionKV::sync_parent_dir(&dir.join(NAME))?;
+    }
+    Ok(())
+}
+
+/// Reads the marker file in `dir`, if there is one. The file is renamed into
+/// place whole, so one that fails its checks was damaged afterwards; it is
+/// ignored, which leaves both the old and the compacted segments in place.
+/// Replaying both gives the same result as either alone, as the compacted
+/// segments come last and hold the latest value of every live key.
+fn read(dir: &Path) -> io::Result<Option<Manifest>> {
+    let body = read_framed(&dir.join(NAME), (MAGIC, MAGIC), None)?;
+    Ok(body.and_then(|body| parse(&body).ok()))
+}
+
+fn parse(mut body: &[u8]) -> io::Result<Manifest> {
+    let mut lists = [Vec::new(), Vec::new()];
+    for ids in &mut lists {
+        let len = body.read_u64::<LittleEndian>()?;
+        if len > body.len() as u64 / 4 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        for _ in 0..len {
+            ids.push(body.read_u32::<LittleEndian>()?);
+        }
+    }
+    if body.read(&mut [0])? != 0 {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let [installed, removed] = lists;
+    Ok(Manifest { installed, removed })
+}
+
+fn remove_if_present(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(read(dir.path()).unwrap(), None);
+
+        let manifest = Manifest {
+            installed: vec![8, 9],
+            removed: vec![1, 2, 3, 7],
+        };
+        commit(dir.path(), &manifest).unwrap();
+        assert_eq!(read(dir.path()).unwrap(), Some(manifest.clone()));
+
+        finish(dir.path(), &manifest).unwrap();
+        assert_eq!(read(dir.path()).unwrap(), None);
+    }
+}
@@ -0,0 +1,128 @@
+//! Value compression. Records carry the codec they were written with in the
+//! top bits of their kind byte, so a store can be reopened with a different
+//! setting and still read everything in its log.
+//!
+//! LZ4 values are stored as the uncompressed length (`u32`, little endian)
+//! followed by a single LZ4 block.
+
+use byteorder::{ByteOrder as _, LittleEndian};
+
+use crate::{ByteStr, ByteString};
+
+/// How values are compressed when they are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum Compression {
+    /// Store values as they are.
+    #[default]
+    None,
+    /// Compress values with the LZ4 block format. Fast, and well suited to
+    /// repetitive text such as JSON.
+    Lz4,
+}
+
+/// Values shorter than this are always stored as they are, as there is too
+/// little in them to compress.
+const MIN_COMPRESSED_LEN: usize = 64;
+
+impl Compression {
+    /// The codec id stored in record headers.
+    pub(crate) fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Lz4 => 1,
+        }
+    }
+
+    pub(crate) fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            1 => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    /// Compresses `value`, or returns `None` if it is better stored as it is.
+    pub(crate) fn compress(self, value: &ByteStr) -> Option<ByteString> {
+        if self == Compression::None
+            || value.len() < MIN_COMPRESSED_LEN
+            || value.len() > u32::MAX as usize
+        {
+            return None;
+        }
+
+        let mut compressed = ByteString::with_capacity(4 + value.len());
+        compressed.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        compressed.extend_from_slice(&lz4_flex::block::compress(value));
+
+        (compressed.len() < value.len()).then_some(compressed)
+    }
+
+    /// Reverses [`compress`](Self::compress), returning `None` if `stored` is
+    /// not valid output of this codec.
+    pub(crate) fn decompress(self, stored: &ByteStr) -> Option<ByteString> {
+        match self {
+            Compression::None => Some(stored.to_vec()),
+            Compression::Lz4 => {
+                let len = LittleEndian::read_u32(stored.get(..4)?) as usize;
+                let block = &stored[4..];
+                // Every byte of a block expands to at most 255 bytes, so a
+                // bad length can not cause a huge allocation.
+                if len > block.len().saturating_mul(255) {
+                    return None;
+                }
+                let mut value = vec![0; len];
+                let written = lz4_flex::block::decompress_into(block, &mut value).ok()?;
+                (written == len).then_some(value)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lz4_round_trips() {
+        let json = br#"{"user":"alice","roles":["admin","editor"],"active":true}"#.repeat(40);
+        let mut state = 0x2545_f491u32;
+        let noise: ByteString = (0..5000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect();
+        let runs: ByteString = (0..3000).map(|i| (i / 300) as u8).collect();
+
+        let compressed = Compression::Lz4.compress(&json).unwrap();
+        assert!(compressed.len() < json.len() / 10);
+        assert_eq!(Compression::Lz4.decompress(&compressed), Some(json));
+
+        let compressed = Compression::Lz4.compress(&runs).unwrap();
+        assert_eq!(Compression::Lz4.decompress(&compressed), Some(runs));
+
+        assert_eq!(Compression::Lz4.compress(&noise), None);
+        assert_eq!(Compression::Lz4.compress(b"short"), None);
+        assert_eq!(Compression::None.compress(&[b'a'; 1000]), None);
+    }
+
+    #[test]
+    fn lz4_rejects_bad_input() {
+        let compressed = Compression::Lz4.compress(&[b'x'; 500]).unwrap();
+        let mut wrong_len = compressed.clone();
+        wrong_len[..4].copy_from_slice(&499u32.to_le_bytes());
+        assert_eq!(Compression::Lz4.decompress(&wrong_len), None);
+        wrong_len[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Compression::Lz4.decompress(&wrong_len), None);
+        assert_eq!(
+            Compression::Lz4.decompress(&compressed[..compressed.len() - 1]),
+            None
+        );
+        // A match reaching back before the start of the output.
+        assert_eq!(Compression::Lz4.decompress(b"\x04\0\0\0\x00\x01\x00"), None);
+        assert_eq!(Compression::Lz4.decompress(b"\x01"), None);
+    }
+}
@@ -0,0 +1,236 @@
+//! ChaCha20-Poly1305 authenticated encryption, as specified in RFC 8439, for
+//! stores opened with an encryption key.
+
+use std::{fmt, io};
+
+use chacha20::{
+    cipher::{KeyIvInit as _, StreamCipher as _, StreamCipherSeek as _},
+    ChaCha20,
+};
+use chacha20poly1305::{aead::AeadInOut as _, ChaCha20Poly1305, KeyInit as _};
+use poly1305::{universal_hash::UniversalHash as _, Poly1305};
+
+pub(crate) const KEY_LEN: usize = 32;
+pub(crate) const NONCE_LEN: usize = 12;
+pub(crate) const TAG_LEN: usize = 16;
+
+/// Longest message that can be sealed under one nonce. ChaCha20's 32-bit
+/// block counter runs out after this, and would repeat the keystream if it
+/// wrapped around.
+pub(crate) const MAX_SEALED_LEN: u64 = u32::MAX as u64 * 64 - 1;
+
+/// Size of a ChaCha20 keystream block.
+const BLOCK_LEN: u64 = 64;
+
+/// Size of a Poly1305 block.
+const MAC_BLOCK_LEN: usize = 16;
+
+/// A ChaCha20-Poly1305 key. `Debug` leaves the key out.
+#[derive(Clone)]
+pub(crate) struct Cipher {
+    key: [u8; KEY_LEN],
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Cipher { .. }")
+    }
+}
+
+impl Cipher {
+    pub(crate) fn new(key: [u8; KEY_LEN]) -> Self {
+        Cipher { key }
+    }
+
+    /// Returns a random nonce. With 96 random bits, a key can seal billions
+    /// of records before a repeat becomes a concern.
+    pub(crate) fn nonce() -> io::Result<[u8; NONCE_LEN]> {
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::fill(&mut nonce).map_err(io::Error::other)?;
+        Ok(nonce)
+    }
+
+    /// Encrypts `data` in place and returns the tag that authenticates it
+    /// together with `aad`. Fails if `data` is longer than
+    /// [`MAX_SEALED_LEN`].
+    pub(crate) fn seal(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        data: &mut [u8],
+    ) -> io::Result<[u8; TAG_LEN]> {
+        let tag = ChaCha20Poly1305::new(&self.key.into())
+            .encrypt_inout_detached(&(*nonce).into(), aad, data.into())
+            .map_err(|_| too_long())?;
+        Ok(tag.into())
+    }
+
+    /// Starts sealing a message that arrives in pieces.
+    pub(crate) fn sealer(&self, nonce: &[u8; NONCE_LEN], aad: &[u8]) -> Sealer {
+        // The construction of RFC 8439, section 2.8: the first keystream
+        // block keys Poly1305 and the message is encrypted from the second.
+        let mut cipher = ChaCha20::new(&self.key.into(), &(*nonce).into());
+        let mut mac_key = poly1305::Key::default();
+        cipher.apply_keystream(&mut mac_key);
+        cipher.seek(BLOCK_LEN);
+        let mut mac = Poly1305::new(&mac_key);
+        mac.update_padded(aad);
+
+        Sealer {
+            cipher,
+            mac,
+            partial: [0; MAC_BLOCK_LEN],
+            partial_len: 0,
+            aad_len: aad.len() as u64,
+            data_len: 0,
+        }
+    }
+
+    /// Checks `tag` and decrypts `data` in place. Returns `false`, leaving
+    /// `data` alone, if the tag does not match, which means either a
+    /// different key or tampered data.
+    pub(crate) fn open(
+        &self,
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        data: &mut [u8],
+        tag: &[u8; TAG_LEN],
+    ) -> bool {
+        ChaCha20Poly1305::new(&self.key.into())
+            .decrypt_inout_detached(&(*nonce).into(), aad, data.into(), &(*tag).into())
+            .is_ok()
+    }
+}
+
+fn too_long() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "messages longer than {} bytes can not be encrypted",
+            MAX_SEALED_LEN
+        ),
+    )
+}
+
+/// Encryption of a message in pieces, as started by [`Cipher::sealer`]. The
+/// result is the same as sealing the whole message at once.
+pub(crate) struct Sealer {
+    cipher: ChaCha20,
+    mac: Poly1305,
+    /// Ciphertext not yet making up a whole Poly1305 block, which would
+    /// otherwise be zero padded.
+    partial: [u8; MAC_BLOCK_LEN],
+    partial_len: usize,
+    aad_len: u64,
+    data_len: u64,
+}
+
+impl Sealer {
+    /// Encrypts the next piece of the message in place. Fails, leaving
+    /// `data` alone, if the message would grow longer than
+    /// [`MAX_SEALED_LEN`].
+    pub(crate) fn update(&mut self, data: &mut [u8]) -> io::Result<()> {
+        let data_len = self.data_len + data.len() as u64;
+        if data_len > MAX_SEALED_LEN {
+            return Err(too_long());
+        }
+        self.cipher
+            .try_apply_keystream(data)
+            .map_err(|_| too_long())?;
+        self.data_len = data_len;
+
+        let mut data: &[u8] = data;
+        if self.partial_len > 0 {
+            let take = data.len().min(MAC_BLOCK_LEN - self.partial_len);
+            self.partial[self.partial_len..self.partial_len + take].copy_from_slice(&data[..take]);
+            self.partial_len += take;
+            data = &data[take..];
+            if self.partial_len < MAC_BLOCK_LEN {
+                return Ok(());
+            }
+            self.mac.update_padded(&self.partial);
+            self.partial_len = 0;
+        }
+
+        // Whole blocks need no padding.
+        let whole = data.len() - data.len() % MAC_BLOCK_LEN;
+        self.mac.update_padded(&data[..whole]);
+        let rest = &data[whole..];
+        self.partial[..rest.len()].copy_from_slice(rest);
+        self.partial_len = rest.len();
+        Ok(())
+    }
+
+    /// Returns the tag for the whole message.
+    pub(crate) fn finish(mut self) -> [u8; TAG_LEN] {
+        self.mac.update_padded(&self.partial[..self.partial_len]);
+        let mut lengths = [0; MAC_BLOCK_LEN];
+        lengths[..8].copy_from_slice(&self.aad_len.to_le_bytes());
+        lengths[8..].copy_from_slice(&self.data_len.to_le_bytes());
+        self.mac.update_padded(&lengths);
+        self.mac.finalize().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_rfc_8439_test_vector() {
+        // Section 2.8.2.
+        let key: [u8; KEY_LEN] = std::array::from_fn(|i| 0x80 + i as u8);
+        let cipher = Cipher::new(key);
+        let nonce = [
+            0x07, 0, 0, 0, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47,
+        ];
+        let aad = [
+            0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+        ];
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only \
+                          one tip for the future, sunscreen would be it.";
+
+        let mut data = plaintext.to_vec();
+        let tag = cipher.seal(&nonce, &aad, &mut data).unwrap();
+        assert_eq!(
+            data[..16],
+            [
+                0xd3, 0x1a, 0x8d, 0x34, 0x64, 0x8e, 0x60, 0xdb, 0x7b, 0x86, 0xaf, 0xbc, 0x53, 0xef,
+                0x7e, 0xc2
+            ]
+        );
+        assert_eq!(
+            tag,
+            [
+                0x1a, 0xe1, 0x0b, 0x59, 0x4f, 0x09, 0xe2, 0x6a, 0x7e, 0x90, 0x2e, 0xcb, 0xd0, 0x60,
+                0x06, 0x91
+            ]
+        );
+
+        let mut pieces = plaintext.to_vec();
+        let mut sealer = cipher.sealer(&nonce, &aad);
+        for chunk in pieces.chunks_mut(7) {
+            sealer.update(chunk).unwrap();
+        }
+        assert_eq!(sealer.finish(), tag);
+        assert_eq!(pieces, data);
+
+        let mut tampered = data.clone();
+        tampered[0] ^= 1;
+        assert!(!cipher.open(&nonce, &aad, &mut tampered, &tag));
+        assert!(!Cipher::new([0; KEY_LEN]).open(&nonce, &aad, &mut data, &tag));
+        assert!(cipher.open(&nonce, &aad, &mut data, &tag));
+        assert_eq!(data, plaintext);
+    }
+
+    #[test]
+    fn sealing_stops_before_the_block_counter_wraps() {
+        let cipher = Cipher::new([7; KEY_LEN]);
+        let mut sealer = cipher.sealer(&[0; NONCE_LEN], b"");
+        sealer.update(&mut [0; 100]).unwrap();
+        sealer.data_len = MAX_SEALED_LEN - 10;
+        let mut data = [1; 11];
+        assert!(sealer.update(&mut data).is_err());
+        assert_eq!(data, [1; 11]);
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    fs::File,
+    io,
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// When writes to the log are forced to stable storage with `fsync`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Sync before every write returns. An acknowledged write survives a
+    /// power loss.
+    Always,
+    /// Sync from a background thread at this interval. Writes acknowledged
+    /// within the last interval may be lost.
+    Interval(Duration),
+    /// Sync from a background thread once this many bytes have been written
+    /// since the last sync.
+    Bytes(u64),
+    /// Never sync explicitly and leave it to the operating system.
+    #[default]
+    Never,
+}
+
+/// Thread that syncs the log on behalf of the `Interval` and `Bytes` policies.
+#[derive(Debug)]
+pub(crate) struct BackgroundSync {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+#[derive(Debug)]
+struct State {
+    file: Arc<File>,
+    /// Bytes written since the last sync started.
+    pending: u64,
+    /// First error hit by the thread, reported on the next write or sync.
+    error: Option<io::Error>,
+    shutdown: bool,
+}
+
+impl BackgroundSync {
+    /// Starts a sync thread for `durability`, or returns `None` if the policy
+    /// does not need one.
+    pub(crate) fn start(durability: Durability, file: &File) -> io::Result<Option<Self>> {
+        if !matches!(durability, Durability::Interval(_) | Durability::Bytes(_)) {
+            return Ok(None);
+        }
+
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                file: Arc::new(file.try_clone()?),
+                pending: 0,
+                error: None,
+                shutdown: false,
+            }),
+            wake: Condvar::new(),
+        });
+
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("actionkv-sync".into())
+                .spawn(move || shared.run(durability))?
+        };
+
+        Ok(Some(BackgroundSync {
+            shared,
+            thread: Some(thread),
+        }))
+    }
+
+    /// Records that `len` bytes were written, waking the thread if that
+    /// crosses the `Bytes` threshold.
+    pub(crate) fn written(&self, len: u64) -> io::Result<()> {
+        let mut state = self.shared.lock();
+        if let Some(err) = state.error.take() {
+            return Err(err);
+        }
+
+        state.pending += len;
+        self.shared.wake.notify_one();
+        Ok(())
+    }
+
+    /// Called after the caller synced the file itself.
+    pub(crate) fn synced(&self) -> io::Result<()> {
+        let mut state = self.shared.lock();
+        state.pending = 0;
+        match state.error.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// Points the thread at a new log file, after compaction replaced it.
+    pub(crate) fn replace_file(&self, file: &File) -> io::Result<()> {
+        let file = Arc::new(file.try_clone()?);
+        self.shared.lock().file = file;
+        Ok(())
+    }
+}
+
+impl Drop for BackgroundSync {
+    fn drop(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.wake.notify_one();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn run(&self, durability: Durability) {
+        let mut state = self.lock();
+
+        loop {
+            state = match durability {
+                Durability::Interval(interval) => {
+                    self.wake
+                        .wait_timeout_while(state, interval, |state| !state.shutdown)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0
+                }
+                Durability::Bytes(bytes) => self
+                    .wake
+                    .wait_while(state, |state| !state.shutdown && state.pending < bytes)
+                    .unwrap_or_else(PoisonError::into_inner),
+                Durability::Always | Durability::Never => return,
+            };
+
+            if state.pending > 0 {
+                let file = Arc::clone(&state.file);
+                state.pending = 0;
+                drop(state);
+
+                let result = file.sync_data();
+
+                state = self.lock();
+                if let Err(err) = result {
+                    state.error.get_or_insert(err);
+                }
+            }
+
+            if state.shutdown {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::{error::Error, fmt, io};
+
+/// Errors returned by [`ActionKV`](crate::ActionKV).
+///
+/// Corruption is reported rather than panicked on, so callers can decide
+/// whether to skip the record, repair the log or give up.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ActionKVError {
+    /// The underlying file operation failed.
+    Io(io::Error),
+    /// The record at `offset` does not match its stored checksum.
+    ChecksumMismatch {
+        offset: u64,
+        expected: u32,
+        actual: u32,
+    },
+    /// The log ends part way through the record starting at `offset`.
+    TruncatedRecord { offset: u64 },
+    /// A key or value of `len` bytes is larger than the format allows.
+    /// `offset` is set when the record was read from the log.
+    OversizeRecord {
+        offset: Option<u64>,
+        len: u64,
+        max: u64,
+    },
+    /// The header of the record at `offset` could not be understood.
+    BadHeader { offset: u64, reason: String },
+    /// The value of the record at `offset` passed its checksum but could not
+    /// be decoded.
+    BadValue { offset: u64, reason: String },
+    /// The record at `offset` is encrypted with a different key than the
+    /// store was opened with, or has been tampered with.
+    WrongKey { offset: u64 },
+    /// The record at `offset` is encrypted but the store was opened without
+    /// a key.
+    KeyRequired { offset: u64 },
+    /// The log does not start with a valid file header.
+    BadFileHeader { reason: String },
+    /// The log was written in a format, or with features, that this version
+    /// does not support.
+    UnsupportedFormat { reason: String },
+    /// The segment holding a record is not in the store's directory, for
+    /// instance because it was archived.
+    MissingSegment { segment: u32 },
+    /// The record at `offset` in segment `segment` is damaged and `after`
+    /// bytes of the log follow it, so it is not a torn final write that
+    /// recovery could safely truncate. Repairing the segment would discard
+    /// all of them.
+    DamagedSegment {
+        segment: u32,
+        offset: u64,
+        after: u64,
+        reason: String,
+    },
+    /// A transaction read `key`, which another writer changed before the
+    /// transaction could commit. Nothing the transaction wrote was applied,
+    /// and it can be retried.
+    Conflict { key: Vec<u8> },
+    /// A key has merge operands to fold into its value, or a merge was
+    /// requested, but the store was opened without a merge operator.
+    NoMergeOperator,
+    /// [`incr`](crate::ActionKV::incr) or [`decr`](crate::ActionKV::decr)
+    /// was asked to change `key`, whose value is not a counter or would
+    /// overflow.
+    BadCounter { key: Vec<u8>, reason: String },
+    /// Another process has the store open for writing.
+    Locked,
+    /// A write was attempted through a store opened with
+    /// [`open_read_only`](crate::ActionKV::open_read_only).
+    ReadOnly,
+    /// A server reported this error in reply to a client request.
+    Remote(String),
+}
+
+pub type Result<T> = std::result::Result<T, ActionKVError>;
+
+impl ActionKVError {
+    /// Whether the error describes damaged data in the log, as opposed to a
+    /// failure of the underlying file operation.
+    pub fn is_corruption(&self) -> bool {
+        matches!(
+            self,
+            ActionKVError::ChecksumMismatch { .. }
+                | ActionKVError::TruncatedRecord { .. }
+                | ActionKVError::OversizeRecord {
+                    offset: Some(_),
+                    ..
+                }
+                | ActionKVError::BadHeader { .. }
+                | ActionKVError::BadValue { .. }
+                | ActionKVError::DamagedSegment { .. }
+        )
+    }
+}
+
+impl fmt::Display for ActionKVError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionKVError::Io(err) => write!(f, "I/O error: {}", err),
+            ActionKVError::ChecksumMismatch {
+                offset,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "data corruption encountered at offset {} ({:08x} != {:08x})",
+                offset, actual, expected
+            ),
+            ActionKVError::TruncatedRecord { offset } => {
+                write!(f, "truncated record at offset {}", offset)
+            }
+            ActionKVError::OversizeRecord {
+                offset: Some(offset),
+                len,
+                max,
+            } => write!(
+                f,
+                "record at offset {} is {} bytes long, the limit is {}",
+                offset, len, max
+            ),
+            ActionKVError::OversizeRecord {
+                offset: None,
+                len,
+                max,
+            } => write!(f, "{} bytes is more than the limit of {}", len, max),
+            ActionKVError::BadHeader { offset, reason } => {
+                write!(f, "bad record header at offset {}: {}", offset, reason)
+            }
+            ActionKVError::BadValue { offset, reason } => {
+                write!(f, "bad record value at offset {}: {}", offset, reason)
+            }
+            ActionKVError::WrongKey { offset } => write!(
+                f,
+                "record at offset {} does not decrypt with the given key",
+                offset
+            ),
+            ActionKVError::KeyRequired { offset } => write!(
+                f,
+                "record at offset {} is encrypted but no key was given",
+                offset
+            ),
+            ActionKVError::BadFileHeader { reason } => write!(f, "bad file header: {}", reason),
+            ActionKVError::UnsupportedFormat { reason } => {
+                write!(f, "unsupported log format: {}", reason)
+            }
+            ActionKVError::MissingSegment { segment } => {
+                write!(f, "segment {} is missing", segment)
+            }
+            ActionKVError::DamagedSegment {
+                segment,
+                offset,
+                after,
+                reason,
+            } => write!(
+                f,
+                "segment {} is damaged at offset {}, with {} more bytes after it: {}",
+                segment, offset, after, reason
+            ),
+            ActionKVError::Conflict { key } => write!(
+                f,
+                "transaction conflicts with a write to {:?}",
+                String::from_utf8_lossy(key)
+            ),
+            ActionKVError::NoMergeOperator => {
+                f.write_str("the store was opened without a merge operator")
+            }
+            ActionKVError::BadCounter { key, reason } => write!(
+                f,
+                "can not change the counter {:?}: {}",
+                String::from_utf8_lossy(key),
+                reason
+            ),
+            ActionKVError::Locked => {
+                f.write_str("the store is locked by another process writing to it")
+            }
+            ActionKVError::ReadOnly => f.write_str("the store was opened read-only"),
+            ActionKVError::Remote(message) => write!(f, "server error: {}", message),
+        }
+    }
+}
+
+impl Error for ActionKVError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ActionKVError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Returned by [`ActionKV::compare_and_swap`](crate::ActionKV::compare_and_swap)
+/// when the key does not hold the value that was expected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompareAndSwapError {
+    /// The key's current value, or `None` if it is absent.
+    pub current: Option<Vec<u8>>,
+}
+
+impl fmt::Display for CompareAndSwapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.current {
+            Some(current) => write!(
+                f,
+                "compare and swap failed: the current value is {:?}",
+                String::from_utf8_lossy(current)
+            ),
+            None => f.write_str("compare and swap failed: the key is absent"),
+        }
+    }
+}
+
+impl Error for CompareAndSwapError {}
+
+impl From<io::Error> for ActionKVError {
+    fn from(err: io::Error) -> Self {
+        ActionKVError::Io(err)
+    }
+}
@@ -0,0 +1,286 @@
+//! Header at the start of every log file.
+//!
+//! Layout: magic, format version (`u16`), checksum algorithm (`u8`), feature
+//! flags (`u8`), a key check, then a CRC32 of the preceding bytes. The key
+//! check is the nonce and tag from sealing an empty message, with the fields
+//! before it as associated data, under the store's encryption key. It is
+//! zeroed in logs that are not encrypted.
+//!
+//! Version 1 logs store key and value lengths as `u32`s; version 2 stores
+//! them as varints, which lifts the 4 GiB limit and shrinks small records.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read as _, Seek as _, SeekFrom, Write as _},
+    path::Path,
+};
+
+use byteorder::{ByteOrder as _, LittleEndian};
+
+use crate::{
+    crypto::{Cipher, NONCE_LEN, TAG_LEN},
+    ActionKVError, Compression, Encoding, LengthFormat, Result, CRC32,
+};
+
+pub(crate) const MAGIC: &[u8; 4] = b"AKVL";
+
+/// The format written by this version of the crate. Logs with a later
+/// version are refused.
+pub(crate) const FORMAT_VERSION: u16 = 2;
+
+/// Size of the header, and so the position of the first record.
+pub(crate) const LEN: u64 = 40;
+
+/// Length of the fields covered by the key check.
+const FIELDS_LEN: usize = 8;
+const KEY_CHECK_LEN: usize = NONCE_LEN + TAG_LEN;
+
+/// Id of CRC-32/CKSUM, the only record checksum so far.
+const CHECKSUM_CRC32: u8 = 0;
+
+/// Some records may hold compressed values.
+const COMPRESSED: u8 = 1;
+/// Records are encrypted, and the header holds a key check.
+const ENCRYPTED: u8 = 1 << 1;
+const KNOWN_FLAGS: u8 = COMPRESSED | ENCRYPTED;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FileHeader {
+    version: u16,
+    flags: u8,
+    key_check: [u8; KEY_CHECK_LEN],
+}
+
+impl FileHeader {
+    /// The header for a log whose records are all written with `encoding`.
+    pub(crate) fn new(encoding: &Encoding) -> io::Result<Self> {
+        let mut header = FileHeader {
+            version: FORMAT_VERSION,
+            flags: 0,
+            key_check: [0; KEY_CHECK_LEN],
+        };
+        header.enable(encoding)?;
+        Ok(header)
+    }
+
+    /// Sets the flags for the features `encoding` uses, returning whether any
+    /// were not already set.
+    fn enable(&mut self, encoding: &Encoding) -> io::Result<bool> {
+        let before = self.flags;
+        if encoding.compression != Compression::None {
+            self.flags |= COMPRESSED;
+        }
+        if let Some(cipher) = &encoding.cipher {
+            if self.flags & ENCRYPTED == 0 {
+                self.flags |= ENCRYPTED;
+                let nonce = Cipher::nonce()?;
+                let tag = cipher.seal(&nonce, &self.fields(), &mut [])?;
+                self.key_check[..NONCE_LEN].copy_from_slice(&nonce);
+                self.key_check[NONCE_LEN..].copy_from_slice(&tag);
+            }
+        }
+        Ok(self.flags != before)
+    }
+
+    fn fields(&self) -> [u8; FIELDS_LEN] {
+        let mut fields = [0; FIELDS_LEN];
+        fields[..4].copy_from_slice(MAGIC);
+        LittleEndian::write_u16(&mut fields[4..6], self.version);
+        fields[6] = CHECKSUM_CRC32;
+        fields[7] = self.flags;
+        fields
+    }
+
+    pub(crate) fn encode(&self) -> [u8; LEN as usize] {
+        let mut bytes = [0; LEN as usize];
+        bytes[..FIELDS_LEN].copy_from_slice(&self.fields());
+        bytes[FIELDS_LEN..FIELDS_LEN + KEY_CHECK_LEN].copy_from_slice(&self.key_check);
+        let checksum = CRC32.checksum(&bytes[..FIELDS_LEN + KEY_CHECK_LEN]);
+        LittleEndian::write_u32(&mut bytes[FIELDS_LEN + KEY_CHECK_LEN..], checksum);
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        let bad = |reason: &str| ActionKVError::BadFileHeader {
+            reason: reason.to_string(),
+        };
+
+        if !bytes.starts_with(MAGIC) {
+            return Err(bad(
+                "not an actionkv log, or one written before file headers were added; \
+                 the latter can be converted with `akv_mem FILE upgrade`",
+            ));
+        }
+        if bytes.len() < LEN as usize {
+            return Err(bad("file ends part way through the header"));
+        }
+        let (body, checksum) = bytes[..LEN as usize].split_at(FIELDS_LEN + KEY_CHECK_LEN);
+        if CRC32.checksum(body) != LittleEndian::read_u32(checksum) {
+            return Err(bad("header does not match its checksum"));
+        }
+
+        let unsupported = |reason: String| ActionKVError::UnsupportedFormat { reason };
+        let version = LittleEndian::read_u16(&body[4..6]);
+        if version > FORMAT_VERSION {
+            return Err(unsupported(format!(
+                "format version {} is newer than {}",
+                version, FORMAT_VERSION
+            )));
+        }
+        if version == 0 {
+            return Err(bad("format version 0"));
+        }
+        if body[6] != CHECKSUM_CRC32 {
+            return Err(unsupported(format!(
+                "unknown checksum algorithm {}",
+                body[6]
+            )));
+        }
+        let flags = body[7];
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(unsupported(format!(
+                "unknown feature flags {:#04x}",
+                flags & !KNOWN_FLAGS
+            )));
+        }
+
+        Ok(FileHeader {
+            version,
+            flags,
+            key_check: body[FIELDS_LEN..].try_into().unwrap(),
+        })
+    }
+
+    /// How the records of the log store their lengths.
+    pub(crate) fn lengths(&self) -> LengthFormat {
+        match self.version {
+            1 => LengthFormat::Fixed32,
+            _ => LengthFormat::Varint,
+        }
+    }
+
+    /// Fails if the log is encrypted and `encoding` does not have its key.
+    fn check_key(&self, encoding: &Encoding) -> Result<()> {
+        if self.flags & ENCRYPTED == 0 {
+            return Ok(());
+        }
+
+        let cipher = encoding
+            .cipher
+            .as_ref()
+            .ok_or(ActionKVError::KeyRequired { offset: 0 })?;
+        let (nonce, tag) = self.key_check.split_at(NONCE_LEN);
+        if cipher.open(
+            nonce.try_into().unwrap(),
+            &self.fields(),
+            &mut [],
+            tag.try_into().unwrap(),
+        ) {
+            Ok(())
+        } else {
+            Err(ActionKVError::WrongKey { offset: 0 })
+        }
+    }
+}
+
+/// Whether `file` starts with a header, as opposed to being a log from
+/// before headers were added.
+pub(crate) fn is_present(file: &File) -> io::Result<bool> {
+    let mut magic = [0; MAGIC.len()];
+    let mut reader = file;
+    reader.seek(SeekFrom::Start(0))?;
+    match reader.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Reads and validates the header of `file`, and checks that `encoding` has
+/// the key for it. Returns `None` if the file is empty.
+pub(crate) fn read(file: &File, encoding: &Encoding) -> Result<Option<FileHeader>> {
+    let mut bytes = Vec::new();
+    let mut reader = file;
+    reader.seek(SeekFrom::Start(0))?;
+    reader.take(LEN).read_to_end(&mut bytes)?;
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+
+    let header = FileHeader::decode(&bytes)?;
+    header.check_key(encoding)?;
+    Ok(Some(header))
+}
+
+/// Writes a header to the empty log `file`, or checks the existing one
+/// against `encoding` and records any features it adds. Returns the header.
+pub(crate) fn prepare(file: &File, path: &Path, encoding: &Encoding) -> Result<FileHeader> {
+    let mut header = match read(file, encoding)? {
+        Some(header) => header,
+        None => {
+            let header = FileHeader::new(encoding)?;
+            let mut writer = file;
+            writer.write_all(&header.encode())?;
+            file.sync_all()?;
+            return Ok(header);
+        }
+    };
+
+    if header.enable(encoding)? {
+        // The log is opened for appending, which would ignore the position.
+        let mut writer = OpenOptions::new().write(true).open(path)?;
+        writer.write_all(&header.encode())?;
+        writer.sync_data()?;
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips_and_is_validated() {
+        let encoding = Encoding {
+            compression: Compression::Lz4,
+            cipher: Some(Cipher::new([1; 32])),
+            ..Encoding::default()
+        };
+        let header = FileHeader::new(&encoding).unwrap();
+        let bytes = header.encode();
+        assert_eq!(FileHeader::decode(&bytes).unwrap(), header);
+        header.check_key(&encoding).unwrap();
+        assert!(matches!(
+            header.check_key(&Encoding::default()),
+            Err(ActionKVError::KeyRequired { offset: 0 })
+        ));
+        let other = Encoding {
+            cipher: Some(Cipher::new([2; 32])),
+            ..Encoding::default()
+        };
+        assert!(matches!(
+            header.check_key(&other),
+            Err(ActionKVError::WrongKey { offset: 0 })
+        ));
+
+        let mut newer = FileHeader::new(&Encoding::default()).unwrap().encode();
+        newer[4] = 3;
+        let checksum = CRC32.checksum(&newer[..36]);
+        newer[36..].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(
+            FileHeader::decode(&newer),
+            Err(ActionKVError::UnsupportedFormat { .. })
+        ));
+
+        let mut damaged = bytes;
+        damaged[10] ^= 1;
+        assert!(matches!(
+            FileHeader::decode(&damaged),
+            Err(ActionKVError::BadFileHeader { .. })
+        ));
+        assert!(matches!(
+            FileHeader::decode(b"\x00\x00\x00\x00garbage"),
+            Err(ActionKVError::BadFileHeader { .. })
+        ));
+    }
+}
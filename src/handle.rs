@@ -0,0 +1,642 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    ops::Bound,
+    sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
+};
+
+use crate::{
+    counter_step, expiry_after, merge::Merges, now_millis, scan::RecordSource, segment,
+    transaction::View, ActionKV, ActionKVError, ByteStr, ByteString, CompareAndSwapError,
+    KeyValuePair, MergeOperator, Position, RecordKind, Result, Snapshot, Transaction, WriteBatch,
+};
+
+/// Cloneable handle to an [`ActionKV`] that can be shared between threads.
+///
+/// Reads take a shared lock on the index and use positional reads against
+/// file descriptors owned by the handle, so any number of them run in
+/// parallel. Writers are serialized; each appends to the log while readers
+/// carry on, and only locks readers out for the moment it takes to publish
+/// the new record in the index.
+///
+/// ```no_run
+/// # use libactionkv::{ActionKV, ActionKVHandle};
+/// let mut store = ActionKV::open(std::path::Path::new("store.akv"))?;
+/// store.load()?;
+/// let handle = ActionKVHandle::new(store);
+///
+/// let reader = handle.clone();
+/// std::thread::spawn(move || reader.get(b"key"));
+/// handle.insert(b"key", b"value")?;
+/// # Ok::<(), libactionkv::ActionKVError>(())
+/// ```
+#[derive(Debug)]
+pub struct ActionKVHandle {
+    shared: Arc<Shared>,
+    /// This handle's own descriptors for the log's segments, opened on first
+    /// use.
+    readers: Mutex<Readers>,
+}
+
+#[derive(Debug, Default)]
+struct Readers {
+    /// The generation of the log the descriptors belong to.
+    generation: u64,
+    files: HashMap<u32, Arc<File>>,
+}
+
+/// Reads records through a handle's descriptors.
+struct HandleSource<'a> {
+    handle: &'a ActionKVHandle,
+    store: &'a ActionKV,
+}
+
+impl RecordSource for HandleSource<'_> {
+    fn read_record(&self, position: Position) -> Result<KeyValuePair> {
+        let file = self.handle.reader(self.store, position.segment)?;
+        ActionKV::read_record_at(&file, position.offset, &self.store.encoding)
+    }
+
+    fn merges(&self) -> (&Merges, Option<&dyn MergeOperator>) {
+        self.store.merges()
+    }
+}
+
+impl View for ActionKVHandle {
+    fn read_live(&self, key: &ByteStr) -> Result<Option<(Position, ByteString)>> {
+        let store = self.shared.read();
+        let position = match store.live_position(key) {
+            Some(position) => position,
+            None => return Ok(None),
+        };
+
+        let source = HandleSource {
+            handle: self,
+            store: &store,
+        };
+        Ok(Some((position, source.read_value(key, position)?.value)))
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    store: RwLock<ActionKV>,
+    /// Held for the whole of every write, so that there is one writer at a
+    /// time even though appends only take `store` for reading.
+    writer: Mutex<()>,
+}
+
+impl ActionKVHandle {
+    /// Wraps a store, which should already be loaded.
+    pub fn new(store: ActionKV) -> Self {
+        ActionKVHandle {
+            shared: Arc::new(Shared {
+                store: RwLock::new(store),
+                writer: Mutex::new(()),
+            }),
+            readers: Mutex::default(),
+        }
+    }
+
+    pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        let store = self.shared.read();
+        let position = match store.index.get(key) {
+            Some(_) if store.is_expired(key, now_millis()) => return Ok(None),
+            Some(position) => *position,
+            None => return Ok(None),
+        };
+
+        let source = HandleSource {
+            handle: self,
+            store: &store,
+        };
+        let kv = source.read_value(key, position)?;
+
+        Ok(Some(kv.value))
+    }
+
+    /// Returns the live key/value pairs whose keys fall within `start..end`,
+    /// or from `start` onwards if `end` is `None`, in key order.
+    pub fn range(&self, start: &ByteStr, end: Option<&ByteStr>) -> Result<Vec<KeyValuePair>> {
+        let store = self.shared.read();
+        let source = HandleSource {
+            handle: self,
+            store: &store,
+        };
+
+        let scan = match end {
+            Some(end) => store.range(start..end),
+            None => store.range(start..),
+        };
+        scan.with_source(&source).collect()
+    }
+
+    /// Returns the live key/value pairs whose keys start with `prefix`, in
+    /// key order.
+    pub fn scan_prefix(&self, prefix: &ByteStr) -> Result<Vec<KeyValuePair>> {
+        let store = self.shared.read();
+        let source = HandleSource {
+            handle: self,
+            store: &store,
+        };
+
+        store.scan_prefix(prefix).with_source(&source).collect()
+    }
+
+    /// See [`ActionKV::snapshot`]. Waits for any write in progress, so that
+    /// the snapshot sees all of it or none of it.
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        let _writer = self.shared.writer();
+        self.shared.read().snapshot()
+    }
+
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        self.shared.read().contains_key(key)
+    }
+
+    /// Returns how many of `keys` exist, counting a key each time it is
+    /// listed. They are all looked up at once, with no write in between.
+    pub fn count_existing(&self, keys: &[&ByteStr]) -> usize {
+        let store = self.shared.read();
+        keys.iter().filter(|key| store.contains_key(key)).count()
+    }
+
+    /// Returns up to `limit` keys, in key order, starting from `start`.
+    /// Unlike the scans this reads nothing but the index.
+    pub fn keys_from(&self, start: Bound<&ByteStr>, limit: usize) -> Vec<ByteString> {
+        let store = self.shared.read();
+        let now = now_millis();
+        store
+            .index
+            .range::<ByteStr, _>((start, Bound::Unbounded))
+            .filter(|(key, _)| !store.is_expired(key, now))
+            .take(limit)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    pub fn insert(&self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.write_record(RecordKind::Value, key, value, None)
+    }
+
+    /// See [`ActionKV::insert_with_ttl`].
+    pub fn insert_with_ttl(&self, key: &ByteStr, value: &ByteStr, ttl: Duration) -> Result<()> {
+        self.write_record(RecordKind::Value, key, value, Some(expiry_after(ttl)))
+    }
+
+    pub fn update(&self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.insert(key, value)
+    }
+
+    pub fn delete(&self, key: &ByteStr) -> Result<()> {
+        self.write_record(RecordKind::Tombstone, key, b"", None)
+    }
+
+    /// Deletes those of `keys` that exist, together, and returns how many
+    /// different keys that was. Writers through other handles to the same
+    /// store wait until it is done, so a key deleted by two calls at once is
+    /// only counted by one.
+    pub fn delete_existing(&self, keys: &[&ByteStr]) -> Result<usize> {
+        let _writer = self.shared.writer();
+        let mut existing: Vec<&ByteStr> = keys
+            .iter()
+            .copied()
+            .filter(|key| self.shared.read().contains_key(key))
+            .collect();
+        existing.sort_unstable();
+        existing.dedup();
+
+        let mut batch = WriteBatch::new();
+        for key in &existing {
+            batch.delete(key);
+        }
+        if !batch.is_empty() {
+            self.append_batch(&batch)?;
+        }
+        Ok(existing.len())
+    }
+
+    /// See [`ActionKV::merge`].
+    pub fn merge(&self, key: &ByteStr, operand: &ByteStr) -> Result<()> {
+        if self.shared.read().merge_operator.is_none() {
+            return Err(ActionKVError::NoMergeOperator);
+        }
+
+        let _writer = self.shared.writer();
+        if self.shared.read().is_expired(key, now_millis()) {
+            self.append_record(RecordKind::Tombstone, key, b"", None)?;
+        }
+        self.append_record(RecordKind::Merge, key, operand, None)
+    }
+
+    /// See [`ActionKV::write_batch`].
+    pub fn write_batch(&self, batch: &WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let _writer = self.shared.writer();
+        self.append_batch(batch)
+    }
+
+    /// See [`ActionKV::transaction`]. The transaction reads each key from
+    /// the store the first time it asks for it, keeping track of where its
+    /// value was, so other writers carry on while it runs, and it fails with
+    /// [`ActionKVError::Conflict`](crate::ActionKVError::Conflict) if one of
+    /// them changed a key that it read. Until then, keys read at different
+    /// times may not all have held their values at once.
+    pub fn transaction<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction<'_>) -> Result<T>,
+    {
+        let generation = self.shared.read().generation;
+        let mut txn = Transaction::new(self, generation);
+        let result = f(&mut txn)?;
+        let commit = txn.finish();
+
+        let _writer = self.shared.writer();
+        commit.check(&self.shared.read())?;
+        if !commit.batch.is_empty() {
+            self.append_batch(&commit.batch)?;
+        }
+        Ok(result)
+    }
+
+    /// Writes a non-empty batch. The caller must hold the writer lock.
+    fn append_batch(&self, batch: &WriteBatch) -> Result<()> {
+        let (body_start, body_len, written, offsets) = {
+            let store = self.shared.read();
+            let (body, offsets) = batch.encode(&store.encoding)?;
+            let (position, written) = store.append_record(RecordKind::Batch, b"", &body, None)?;
+            let body_len = body.len() as u64;
+            let body_start = Position {
+                offset: position.offset + written - body_len,
+                ..position
+            };
+            (body_start, body_len, written, offsets)
+        };
+
+        self.shared
+            .write()
+            .commit_batch(batch, &offsets, body_start, body_len, written)
+    }
+
+    /// See [`ActionKV::sync`].
+    pub fn sync(&self) -> Result<()> {
+        self.shared.read().sync()
+    }
+
+    /// See [`ActionKV::compact`]. Readers carry on while the new segments
+    /// are written and are only locked out while they are swapped in.
+    pub fn compact(&self) -> Result<()> {
+        let _writer = self.shared.writer();
+        self.shared.read().check_writable()?;
+        self.shared.write().roll_over()?;
+        let compacted = self.shared.read().write_compacted()?;
+
+        self.shared.write().install_compacted(compacted)
+    }
+
+    /// See [`ActionKV::compare_and_swap`]. Writers through other handles to
+    /// the same store wait until it is done, so nothing can change `key`
+    /// between the comparison and the write.
+    pub fn compare_and_swap(
+        &self,
+        key: &ByteStr,
+        expected: Option<&ByteStr>,
+        new: Option<&ByteStr>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        let _writer = self.shared.writer();
+        let current = self.get(key)?;
+        if current.as_deref() != expected {
+            return Ok(Err(CompareAndSwapError { current }));
+        }
+
+        match new {
+            Some(value) => {
+                let expires_at = current.and(self.shared.read().expiries.get(key).copied());
+                self.append_record(RecordKind::Value, key, value, expires_at)?
+            }
+            None if current.is_some() => {
+                self.append_record(RecordKind::Tombstone, key, b"", None)?
+            }
+            None => {}
+        }
+        Ok(Ok(()))
+    }
+
+    /// See [`ActionKV::insert_if_absent`].
+    pub fn insert_if_absent(&self, key: &ByteStr, value: &ByteStr) -> Result<Option<ByteString>> {
+        let swapped = self.compare_and_swap(key, None, Some(value))?;
+        Ok(swapped.err().and_then(|err| err.current))
+    }
+
+    /// See [`ActionKV::incr`]. Writers through other handles to the same
+    /// store wait until it is done, so no update is lost.
+    pub fn incr(&self, key: &ByteStr, delta: i64) -> Result<i64> {
+        self.add_to_counter(key, delta, i64::checked_add)
+    }
+
+    /// See [`ActionKV::decr`].
+    pub fn decr(&self, key: &ByteStr, delta: i64) -> Result<i64> {
+        self.add_to_counter(key, delta, i64::checked_sub)
+    }
+
+    fn add_to_counter(
+        &self,
+        key: &ByteStr,
+        delta: i64,
+        op: fn(i64, i64) -> Option<i64>,
+    ) -> Result<i64> {
+        let _writer = self.shared.writer();
+        let current = self.get(key)?;
+        let next = counter_step(key, current.as_deref(), delta, op)?;
+        let expires_at = current.and(self.shared.read().expiries.get(key).copied());
+        self.append_record(RecordKind::Value, key, &next.to_le_bytes(), expires_at)?;
+        Ok(next)
+    }
+
+    fn write_record(
+        &self,
+        kind: RecordKind,
+        key: &ByteStr,
+        value: &ByteStr,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let _writer = self.shared.writer();
+        self.append_record(kind, key, value, expires_at)
+    }
+
+    /// Writes a single record. The caller must hold the writer lock.
+    fn append_record(
+        &self,
+        kind: RecordKind,
+        key: &ByteStr,
+        value: &ByteStr,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let (position, written) = self
+            .shared
+            .read()
+            .append_record(kind, key, value, expires_at)?;
+
+        self.shared
+            .write()
+            .commit_record(kind, key, expires_at, position, written)
+    }
+
+    /// Returns this handle's descriptor for segment `id`, opening it if
+    /// needed. Descriptors are all dropped once compaction has replaced
+    /// segments, so that they do not hold on to the removed files.
+    fn reader(&self, store: &ActionKV, id: u32) -> Result<Arc<File>> {
+        let mut readers = self.readers.lock().unwrap_or_else(PoisonError::into_inner);
+        if readers.generation != store.generation {
+            readers.files.clear();
+            readers.generation = store.generation;
+        }
+        if let Some(file) = readers.files.get(&id) {
+            return Ok(Arc::clone(file));
+        }
+
+        store.segment(id)?;
+        let file = Arc::new(File::open(segment::path(store.path(), id))?);
+        readers.files.insert(id, Arc::clone(&file));
+        Ok(file)
+    }
+}
+
+impl Clone for ActionKVHandle {
+    /// Returns a new handle to the same store, with its own file
+    /// descriptors.
+    fn clone(&self) -> Self {
+        ActionKVHandle {
+            shared: Arc::clone(&self.shared),
+            readers: Mutex::default(),
+        }
+    }
+}
+
+impl Shared {
+    fn read(&self) -> RwLockReadGuard<'_, ActionKV> {
+        self.store.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn write(&self) -> RwLockWriteGuard<'_, ActionKV> {
+        self.store.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn writer(&self) -> MutexGuard<'_, ()> {
+        self.writer.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn handle_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<ActionKVHandle>();
+    }
+
+    #[test]
+    fn readers_run_alongside_a_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ActionKV::options()
+            .segment_size(256)
+            .open(&dir.path().join("store.akv"))
+            .unwrap();
+        let handle = ActionKVHandle::new(store);
+        for i in 0..10u32 {
+            handle
+                .insert(&i.to_be_bytes(), &0u32.to_be_bytes())
+                .unwrap();
+        }
+
+        let writer = {
+            let handle = handle.clone();
+            thread::spawn(move || {
+                for round in 1..=50u32 {
+                    for i in 0..10u32 {
+                        handle
+                            .insert(&i.to_be_bytes(), &round.to_be_bytes())
+                            .unwrap();
+                    }
+                    if round % 20 == 0 {
+                        handle.compact().unwrap();
+                    }
+                }
+            })
+        };
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let handle = handle.clone();
+                thread::spawn(move || {
+                    let mut last = [0u32; 10];
+                    for _ in 0..200 {
+                        for i in 0..10u32 {
+                            let value = handle.get(&i.to_be_bytes()).unwrap().unwrap();
+                            let round = u32::from_be_bytes(value.try_into().unwrap());
+                            assert!(round >= last[i as usize]);
+                            last[i as usize] = round;
+                        }
+                        assert_eq!(handle.scan_prefix(b"").unwrap().len(), 10);
+                    }
+                })
+            })
+            .collect();
+
+        writer.join().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(
+            handle.get(&9u32.to_be_bytes()).unwrap(),
+            Some(50u32.to_be_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn compare_and_swap_counts_without_losing_updates() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        let handle = ActionKVHandle::new(store);
+        assert_eq!(handle.insert_if_absent(b"count", b"0").unwrap(), None);
+        assert_eq!(
+            handle.insert_if_absent(b"count", b"1").unwrap(),
+            Some(b"0".to_vec())
+        );
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let handle = handle.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        let mut current = handle.get(b"count").unwrap();
+                        loop {
+                            let seen = current.as_deref().unwrap();
+                            let count: u32 = std::str::from_utf8(seen).unwrap().parse().unwrap();
+                            let next = (count + 1).to_string();
+                            match handle
+                                .compare_and_swap(b"count", Some(seen), Some(next.as_bytes()))
+                                .unwrap()
+                            {
+                                Ok(()) => break,
+                                Err(err) => current = err.current,
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(handle.get(b"count").unwrap(), Some(b"200".to_vec()));
+
+        let mismatch = handle.compare_and_swap(b"count", Some(b"0"), None).unwrap();
+        assert_eq!(mismatch.unwrap_err().current, Some(b"200".to_vec()));
+        handle
+            .compare_and_swap(b"count", Some(b"200"), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(handle.get(b"count").unwrap(), None);
+
+        handle
+            .insert_with_ttl(b"lease", b"a", Duration::from_secs(3600))
+            .unwrap();
+        let expires_at = handle.shared.read().expiries[&b"lease"[..]];
+        handle
+            .compare_and_swap(b"lease", Some(b"a"), Some(b"b"))
+            .unwrap()
+            .unwrap();
+        let expiries = &handle.shared.read().expiries;
+        assert_eq!(expiries.get(&b"lease"[..]), Some(&expires_at));
+    }
+
+    #[test]
+    fn counters_lose_no_updates() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        let handle = ActionKVHandle::new(store);
+
+        let workers: Vec<_> = (0..4)
+            .map(|_| {
+                let handle = handle.clone();
+                thread::spawn(move || {
+                    for _ in 0..50 {
+                        handle.incr(b"count", 3).unwrap();
+                        handle.decr(b"count", 1).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(handle.incr(b"count", 0).unwrap(), 400);
+    }
+
+    #[test]
+    fn concurrent_deletes_count_each_key_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        let handle = ActionKVHandle::new(store);
+
+        for _ in 0..50 {
+            handle.insert(b"a", b"1").unwrap();
+            handle.insert(b"b", b"2").unwrap();
+            assert_eq!(handle.count_existing(&[b"a", b"a", b"c"]), 2);
+
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    let handle = handle.clone();
+                    thread::spawn(move || {
+                        handle.delete_existing(&[b"a", b"b", b"a", b"c"]).unwrap()
+                    })
+                })
+                .collect();
+            let deleted: usize = workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .sum();
+            assert_eq!(deleted, 2);
+            assert_eq!(handle.count_existing(&[b"a", b"b"]), 0);
+        }
+    }
+
+    #[test]
+    fn snapshot_scans_see_one_write_batch() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        let handle = ActionKVHandle::new(store);
+
+        let writer = {
+            let handle = handle.clone();
+            thread::spawn(move || {
+                for round in 0..200u32 {
+                    let mut batch = WriteBatch::new();
+                    for i in 0..10u32 {
+                        batch.put(&i.to_be_bytes(), &round.to_be_bytes());
+                    }
+                    handle.write_batch(&batch).unwrap();
+                }
+            })
+        };
+
+        for _ in 0..50 {
+            let snapshot = handle.snapshot().unwrap();
+            let rounds: Vec<ByteString> = snapshot
+                .scan_prefix(b"")
+                .map(|kv| kv.unwrap().value)
+                .collect();
+            assert!(rounds.windows(2).all(|pair| pair[0] == pair[1]));
+        }
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+//! Hint files, which list the records of a closed segment without their
+//! values, so that `load` can rebuild the index from them instead of reading
+//! the whole segment.
+//!
+//! Layout: the id (`u32`) and length (`u64`) of the segment described, the
+//! entry count, then `kind | key_len | key | offset | length | expires_at`
+//! for every record in the segment, in log order, with the records of a
+//! batch listed one by one. `length` is that of the record in the log, and
+//! `expires_at` is zero for keys without a time-to-live. The whole is framed
+//! like the index file, and so encrypted along with the store.
+//!
+//! A hint file that does not match the id and length of its segment is
+//! ignored, and the segment read in full.
+
+use std::{
+    fs,
+    io::{self, Read as _, Write as _},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
+
+use crate::{
+    crypto::Cipher,
+    index_file::{read_framed, write_framed},
+    segment, ActionKV, ByteString, Position, Record, RecordKind,
+};
+
+const MAGIC: &[u8; 4] = b"AKVH";
+const ENCRYPTED_MAGIC: &[u8; 4] = b"AKVG";
+
+/// What the index needs to know about a record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Hint {
+    /// `Value`, `Tombstone` or `Merge`.
+    pub(crate) kind: RecordKind,
+    pub(crate) key: ByteString,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+    pub(crate) expires_at: Option<u64>,
+}
+
+impl Hint {
+    /// The hint for `record`, which takes `len` bytes at `offset`.
+    pub(crate) fn new(record: &Record, offset: u64, len: u64) -> Self {
+        Hint {
+            kind: record.kind,
+            key: record.key.clone(),
+            offset,
+            len,
+            expires_at: record.expires_at,
+        }
+    }
+
+    /// The record, without its value, and its position in segment `segment`.
+    pub(crate) fn into_record(self, segment: u32) -> (Position, Record) {
+        let position = Position {
+            segment,
+            offset: self.offset,
+        };
+        let record = Record {
+            kind: self.kind,
+            key: self.key,
+            value: ByteString::new(),
+            expires_at: self.expires_at,
+        };
+        (position, record)
+    }
+}
+
+pub(crate) fn path(dir: &Path, segment: u32) -> PathBuf {
+    segment::path(dir, segment).with_extension("hint")
+}
+
+/// Atomically replaces the hint file of segment `segment`, which is
+/// `segment_len` bytes long.
+pub(crate) fn write(
+    dir: &Path,
+    segment: u32,
+    segment_len: u64,
+    hints: &[Hint],
+    cipher: Option<&Cipher>,
+) -> io::Result<()> {
+    let mut body = Vec::new();
+    body.write_u32::<LittleEndian>(segment)?;
+    body.write_u64::<LittleEndian>(segment_len)?;
+    body.write_u64::<LittleEndian>(hints.len() as u64)?;
+    for hint in hints {
+        body.write_u8(hint.kind as u8)?;
+        body.write_u64::<LittleEndian>(hint.key.len() as u64)?;
+        body.write_all(&hint.key)?;
+        body.write_u64::<LittleEndian>(hint.offset)?;
+        body.write_u64::<LittleEndian>(hint.len)?;
+        body.write_u64::<LittleEndian>(hint.expires_at.unwrap_or(0))?;
+    }
+
+    let path = path(dir, segment);
+    let tmp_path = ActionKV::sibling_path(&path, ".tmp");
+    write_framed(&path, &tmp_path, (MAGIC, ENCRYPTED_MAGIC), body, cipher)
+}
+
+/// Reads the hints for segment `segment`, which is `segment_len` bytes long.
+/// Returns `None` if there is no usable hint file for it.
+pub(crate) fn read(
+    dir: &Path,
+    segment: u32,
+    segment_len: u64,
+    cipher: Option<&Cipher>,
+) -> io::Result<Option<Vec<Hint>>> {
+    let body = read_framed(&path(dir, segment), (MAGIC, ENCRYPTED_MAGIC), cipher)?;
+    Ok(body.and_then(|body| parse(&body, segment, segment_len).ok().flatten()))
+}
+
+fn parse(mut body: &[u8], segment: u32, segment_len: u64) -> io::Result<Option<Vec<Hint>>> {
+    if body.read_u32::<LittleEndian>()? != segment
+        || body.read_u64::<LittleEndian>()? != segment_len
+    {
+        return Ok(None);
+    }
+
+    let len = body.read_u64::<LittleEndian>()?;
+    let mut hints = Vec::new();
+    for _ in 0..len {
+        let kind = match RecordKind::from_u8(body.read_u8()?) {
+            Some(kind @ (RecordKind::Value | RecordKind::Tombstone | RecordKind::Merge)) => kind,
+            _ => return Ok(None),
+        };
+        let key_len = body.read_u64::<LittleEndian>()?;
+        if key_len > body.len() as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut key = vec![0; key_len as usize];
+        body.read_exact(&mut key)?;
+        let offset = body.read_u64::<LittleEndian>()?;
+        let len = body.read_u64::<LittleEndian>()?;
+        let expires_at = match body.read_u64::<LittleEndian>()? {
+            0 => None,
+            expires_at => Some(expires_at),
+        };
+        hints.push(Hint {
+            kind,
+            key,
+            offset,
+            len,
+            expires_at,
+        });
+    }
+
+    Ok(Some(hints))
+}
+
+/// Removes the hint file of segment `segment`, if it has one.
+pub(crate) fn remove(dir: &Path, segment: u32) -> io::Result<()> {
+    match fs::remove_file(path(dir, segment)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hints_round_trip_for_their_own_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let hints = [
+            Hint {
+                kind: RecordKind::Value,
+                key: b"session".to_vec(),
+                offset: 40,
+                len: 20,
+                expires_at: Some(1_700_000_000_000),
+            },
+            Hint {
+                kind: RecordKind::Tombstone,
+                key: b"gone".to_vec(),
+                offset: 60,
+                len: 11,
+                expires_at: None,
+            },
+        ];
+
+        for cipher in [None, Some(Cipher::new([4; 32]))] {
+            write(dir.path(), 3, 71, &hints, cipher.as_ref()).unwrap();
+            let read_back = read(dir.path(), 3, 71, cipher.as_ref()).unwrap();
+            assert_eq!(read_back.as_deref(), Some(&hints[..]));
+            assert_eq!(read(dir.path(), 3, 72, cipher.as_ref()).unwrap(), None);
+            assert_eq!(read(dir.path(), 4, 71, cipher.as_ref()).unwrap(), None);
+        }
+        assert_eq!(read(dir.path(), 3, 71, None).unwrap(), None);
+
+        remove(dir.path(), 3).unwrap();
+        remove(dir.path(), 3).unwrap();
+        assert_eq!(read(dir.path(), 3, 71, None).unwrap(), None);
+    }
+}
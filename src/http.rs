@@ -0,0 +1,672 @@
+//! HTTP/1.1 front end for an [`ActionKV`], for tools that would rather speak
+//! HTTP and JSON than a binary protocol.
+//!
+//! - `GET /kv/{key}` returns the value as the raw response body, or as
+//!   `{"key": ..., "value": ...}` if the request accepts `application/json`.
+//! - `PUT /kv/{key}` stores the raw request body, or the `value` field of a
+//!   JSON object if the body is sent as `application/json`.
+//! - `DELETE /kv/{key}` removes the key.
+//! - `POST /kv/{key}?incr={delta}` and `POST /kv/{key}?decr={delta}` change
+//!   the counter stored at the key, as [`ActionKV::incr`](crate::ActionKV::incr) does, and return
+//!   its new value as `{"value": ...}`. A key that does not hold a counter,
+//!   or would overflow, is `409 Conflict`.
+//! - `GET /kv?prefix={prefix}` returns a JSON array of `{"key", "value"}`
+//!   objects for the keys starting with `prefix`, in key order.
+//!
+//! Keys in paths and queries are percent-encoded, and keys and values in JSON
+//! are base64 strings, so both may hold arbitrary bytes. Missing keys are
+//! `404 Not Found`; damaged data and other storage failures are `500 Internal
+//! Server Error` with a JSON `{"error": ...}` body.
+//!
+//! [`ActionKV`]: crate::ActionKV
+
+use std::{
+    io::{self, BufRead, BufReader, BufWriter, Read as _, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+};
+
+use crate::{ActionKVError, ActionKVHandle, ByteStr, ByteString, KeyValuePair};
+
+/// Longest request body accepted from a client.
+const MAX_BODY_LEN: usize = 256 * 1024 * 1024;
+
+/// Longest request line or header accepted from a client.
+const MAX_LINE_LEN: u64 = 8 * 1024;
+
+/// Most headers accepted in a single request.
+const MAX_HEADERS: usize = 100;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Accepts connections on `listener` forever, serving each from its own
+/// thread with a clone of `store`.
+pub fn serve(listener: TcpListener, store: ActionKVHandle) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let store = store.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle_connection(stream, &store) {
+                eprintln!("connection closed: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, store: &ActionKVHandle) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let request = match read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(()),
+            Err(response) => {
+                response.write(&mut writer, false)?;
+                return writer.flush();
+            }
+        };
+
+        route(store, &request).write(&mut writer, request.keep_alive)?;
+        writer.flush()?;
+        if !request.keep_alive {
+            return Ok(());
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    target: String,
+    headers: Vec<(String, String)>,
+    body: ByteString,
+    keep_alive: bool,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn accepts_json(&self) -> bool {
+        self.header("Accept")
+            .is_some_and(|accept| accept.contains("application/json"))
+    }
+
+    fn sends_json(&self) -> bool {
+        self.header("Content-Type")
+            .is_some_and(|content_type| content_type.starts_with("application/json"))
+    }
+}
+
+/// Reads one request. Returns `None` if the stream ends before it starts,
+/// and the response to send before closing the connection if it is invalid.
+fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>, Response> {
+    let bad_request = |err: io::Error| Response::error(400, &err.to_string());
+
+    if reader.fill_buf().map_err(bad_request)?.is_empty() {
+        return Ok(None);
+    }
+
+    let line = read_line(reader).map_err(bad_request)?;
+    let mut parts = line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) => (method, target, version),
+        _ => return Err(Response::error(400, "malformed request line")),
+    };
+    if version != "HTTP/1.1" && version != "HTTP/1.0" {
+        return Err(Response::error(
+            505,
+            "only HTTP/1.0 and HTTP/1.1 are supported",
+        ));
+    }
+
+    let mut headers = Vec::new();
+    loop {
+        let line = read_line(reader).map_err(bad_request)?;
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(Response::error(431, "too many headers"));
+        }
+        match line.split_once(':') {
+            Some((name, value)) => headers.push((name.to_string(), value.trim().to_string())),
+            None => return Err(Response::error(400, "malformed header")),
+        }
+    }
+
+    let mut request = Request {
+        method: method.to_string(),
+        target: target.to_string(),
+        headers,
+        body: ByteString::new(),
+        keep_alive: false,
+    };
+
+    let connection = request.header("Connection").unwrap_or_default();
+    request.keep_alive = match version {
+        "HTTP/1.1" => !connection.eq_ignore_ascii_case("close"),
+        _ => connection.eq_ignore_ascii_case("keep-alive"),
+    };
+
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(Response::error(501, "transfer encodings are not supported"));
+    }
+    let len = match request.header("Content-Length").map(str::parse::<usize>) {
+        None => 0,
+        Some(Ok(len)) if len <= MAX_BODY_LEN => len,
+        Some(Ok(_)) => return Err(Response::error(413, "request body is too large")),
+        Some(Err(_)) => return Err(Response::error(400, "invalid Content-Length")),
+    };
+    request.body = vec![0; len];
+    reader.read_exact(&mut request.body).map_err(bad_request)?;
+
+    Ok(Some(request))
+}
+
+/// Reads a line, without its line ending.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = ByteString::new();
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+
+    if line.pop() != Some(b'\n') {
+        return Err(match line.len() as u64 {
+            len if len + 1 >= MAX_LINE_LEN => {
+                io::Error::new(io::ErrorKind::InvalidData, "line too long")
+            }
+            _ => io::ErrorKind::UnexpectedEof.into(),
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: ByteString,
+    /// Methods to list in the `Allow` header of a `405 Method Not Allowed`.
+    allow: Option<&'static str>,
+}
+
+impl Response {
+    fn new(status: u16, content_type: &'static str, body: ByteString) -> Self {
+        Response {
+            status,
+            content_type,
+            body,
+            allow: None,
+        }
+    }
+
+    fn json(status: u16, body: String) -> Self {
+        Response::new(status, "application/json", body.into_bytes())
+    }
+
+    fn no_content() -> Self {
+        Response::new(204, "", vec![])
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response::json(status, format!("{{\"error\":{}}}", json_string(message)))
+    }
+
+    fn method_not_allowed(allow: &'static str) -> Self {
+        Response {
+            allow: Some(allow),
+            ..Response::error(405, "method not allowed")
+        }
+    }
+
+    fn write<W: Write>(&self, out: &mut W, keep_alive: bool) -> io::Result<()> {
+        write!(out, "HTTP/1.1 {} {}\r\n", self.status, reason(self.status))?;
+        if self.status != 204 {
+            write!(out, "Content-Type: {}\r\n", self.content_type)?;
+            write!(out, "Content-Length: {}\r\n", self.body.len())?;
+        }
+        if let Some(allow) = self.allow {
+            write!(out, "Allow: {}\r\n", allow)?;
+        }
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        write!(out, "Connection: {}\r\n\r\n", connection)?;
+        out.write_all(&self.body)
+    }
+}
+
+impl From<ActionKVError> for Response {
+    fn from(err: ActionKVError) -> Self {
+        let status = match err {
+            ActionKVError::OversizeRecord { offset: None, .. } => 413,
+            ActionKVError::BadCounter { .. } => 409,
+            _ => 500,
+        };
+        Response::error(status, &err.to_string())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        413 => "Content Too Large",
+        431 => "Request Header Fields Too Large",
+        501 => "Not Implemented",
+        505 => "HTTP Version Not Supported",
+        _ => "Internal Server Error",
+    }
+}
+
+fn route(store: &ActionKVHandle, request: &Request) -> Response {
+    let (path, query) = request
+        .target
+        .split_once('?')
+        .unwrap_or((&request.target, ""));
+
+    let result = if path == "/kv" {
+        match request.method.as_str() {
+            "GET" => list(store, query),
+            _ => Ok(Response::method_not_allowed("GET")),
+        }
+    } else if let Some(key) = path.strip_prefix("/kv/") {
+        let key = match percent_decode(key, false) {
+            Some(key) => key,
+            None => return Response::error(400, "malformed percent-encoding in key"),
+        };
+        match request.method.as_str() {
+            "GET" => get(store, request, &key),
+            "PUT" => put(store, request, &key),
+            "DELETE" => delete(store, &key),
+            "POST" => add_to_counter(store, query, &key),
+            _ => Ok(Response::method_not_allowed("GET, PUT, POST, DELETE")),
+        }
+    } else {
+        Ok(Response::error(404, "no such resource"))
+    };
+
+    result.unwrap_or_else(Response::from)
+}
+
+fn get(store: &ActionKVHandle, request: &Request, key: &ByteStr) -> crate::Result<Response> {
+    let value = match store.get(key)? {
+        Some(value) => value,
+        None => return Ok(Response::error(404, "no such key")),
+    };
+
+    if request.accepts_json() {
+        let pair = KeyValuePair {
+            key: key.to_vec(),
+            value,
+        };
+        Ok(Response::json(200, pair_to_json(&pair)))
+    } else {
+        Ok(Response::new(200, "application/octet-stream", value))
+    }
+}
+
+fn put(store: &ActionKVHandle, request: &Request, key: &ByteStr) -> crate::Result<Response> {
+    if !request.sends_json() {
+        store.insert(key, &request.body)?;
+        return Ok(Response::no_content());
+    }
+
+    let fields = match parse_json_object(&request.body) {
+        Some(fields) => fields,
+        None => {
+            return Ok(Response::error(
+                400,
+                "body must be a JSON object of strings",
+            ))
+        }
+    };
+    let value = fields
+        .iter()
+        .find(|(name, _)| name == "value")
+        .and_then(|(_, value)| base64_decode(value));
+    match value {
+        Some(value) => {
+            store.insert(key, &value)?;
+            Ok(Response::no_content())
+        }
+        None => Ok(Response::error(400, "\"value\" must be a base64 string")),
+    }
+}
+
+fn delete(store: &ActionKVHandle, key: &ByteStr) -> crate::Result<Response> {
+    if !store.contains_key(key) {
+        return Ok(Response::error(404, "no such key"));
+    }
+    store.delete(key)?;
+    Ok(Response::no_content())
+}
+
+fn add_to_counter(store: &ActionKVHandle, query: &str, key: &ByteStr) -> crate::Result<Response> {
+    let count = match query.split_once('=') {
+        Some((op @ ("incr" | "decr"), delta)) => match delta.parse() {
+            Ok(delta) if op == "incr" => store.incr(key, delta)?,
+            Ok(delta) => store.decr(key, delta)?,
+            Err(_) => return Ok(Response::error(400, "the delta must be an integer")),
+        },
+        _ => {
+            return Ok(Response::error(
+                400,
+                "expected ?incr={delta} or ?decr={delta}",
+            ))
+        }
+    };
+    Ok(Response::json(200, format!("{{\"value\":{}}}", count)))
+}
+
+fn list(store: &ActionKVHandle, query: &str) -> crate::Result<Response> {
+    let mut prefix = ByteString::new();
+    for param in query.split('&') {
+        if let Some(value) = param.strip_prefix("prefix=") {
+            prefix = match percent_decode(value, true) {
+                Some(prefix) => prefix,
+                None => return Ok(Response::error(400, "malformed percent-encoding in prefix")),
+            };
+        }
+    }
+
+    let pairs = store.scan_prefix(&prefix)?;
+    let objects: Vec<String> = pairs.iter().map(pair_to_json).collect();
+    Ok(Response::json(200, format!("[{}]", objects.join(","))))
+}
+
+fn pair_to_json(pair: &KeyValuePair) -> String {
+    format!(
+        "{{\"key\":\"{}\",\"value\":\"{}\"}}",
+        base64_encode(&pair.key),
+        base64_encode(&pair.value)
+    )
+}
+
+/// Decodes `%XX` escapes, and `+` as a space if `plus_as_space` is set as it
+/// is in query strings.
+fn percent_decode(encoded: &str, plus_as_space: bool) -> Option<ByteString> {
+    let mut decoded = ByteString::with_capacity(encoded.len());
+    let mut bytes = encoded.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let high = (bytes.next()? as char).to_digit(16)?;
+                let low = (bytes.next()? as char).to_digit(16)?;
+                decoded.push((high * 16 + low) as u8);
+            }
+            b'+' if plus_as_space => decoded.push(b' '),
+            _ => decoded.push(byte),
+        }
+    }
+    Some(decoded)
+}
+
+fn base64_encode(bytes: &ByteStr) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0u32, |group, (i, byte)| {
+            group | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let sextet = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[sextet as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+fn base64_decode(encoded: &str) -> Option<ByteString> {
+    let encoded = encoded.as_bytes();
+    if !encoded.len().is_multiple_of(4) {
+        return None;
+    }
+
+    let mut decoded = ByteString::with_capacity(encoded.len() / 4 * 3);
+    for (n, chunk) in encoded.chunks(4).enumerate() {
+        let last = n == encoded.len() / 4 - 1;
+        let padding = chunk.iter().rev().take_while(|byte| **byte == b'=').count();
+        if padding > 2 || (padding > 0 && !last) {
+            return None;
+        }
+
+        let mut group = 0u32;
+        for byte in &chunk[..4 - padding] {
+            let sextet = BASE64_ALPHABET.iter().position(|c| c == byte)?;
+            group = group << 6 | sextet as u32;
+        }
+        group <<= 6 * padding;
+        decoded.extend_from_slice(&group.to_be_bytes()[1..4 - padding]);
+    }
+    Some(decoded)
+}
+
+fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Parses a flat JSON object whose values are all strings, which is all that
+/// `PUT` needs.
+fn parse_json_object(body: &ByteStr) -> Option<Vec<(String, String)>> {
+    let text = std::str::from_utf8(body).ok()?;
+    let mut chars = text.trim().chars().peekable();
+    let mut fields = Vec::new();
+
+    let skip_whitespace = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+    let parse_string = |chars: &mut std::iter::Peekable<std::str::Chars>| -> Option<String> {
+        if chars.next()? != '"' {
+            return None;
+        }
+        let mut s = String::new();
+        loop {
+            match chars.next()? {
+                '"' => return Some(s),
+                '\\' => s.push(match chars.next()? {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    'b' => '\u{8}',
+                    'f' => '\u{c}',
+                    'u' => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                    }
+                    c @ ('"' | '\\' | '/') => c,
+                    _ => return None,
+                }),
+                c => s.push(c),
+            }
+        }
+    };
+
+    if chars.next()? != '{' {
+        return None;
+    }
+    skip_whitespace(&mut chars);
+    if chars.next_if_eq(&'}').is_none() {
+        loop {
+            skip_whitespace(&mut chars);
+            let name = parse_string(&mut chars)?;
+            skip_whitespace(&mut chars);
+            if chars.next()? != ':' {
+                return None;
+            }
+            skip_whitespace(&mut chars);
+            fields.push((name, parse_string(&mut chars)?));
+            skip_whitespace(&mut chars);
+            match chars.next()? {
+                ',' => continue,
+                '}' => break,
+                _ => return None,
+            }
+        }
+    }
+
+    match chars.next() {
+        Some(_) => None,
+        None => Some(fields),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::OpenOptions,
+        io::{Seek as _, SeekFrom},
+        net::SocketAddr,
+    };
+
+    use super::*;
+    use crate::ActionKV;
+
+    #[test]
+    fn base64_round_trips() {
+        for len in 0..=7u8 {
+            let bytes: Vec<u8> = (0..len).map(|i| i.wrapping_mul(97)).collect();
+            assert_eq!(base64_decode(&base64_encode(&bytes)), Some(bytes));
+        }
+        assert_eq!(base64_encode(b"\x00\xff"), "AP8=");
+        assert_eq!(base64_decode("aGk="), Some(b"hi".to_vec()));
+        assert_eq!(base64_decode("aGk"), None);
+        assert_eq!(base64_decode("a=Gk"), None);
+        assert_eq!(base64_decode("aG!="), None);
+    }
+
+    fn start_server(store: ActionKV) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, ActionKVHandle::new(store)));
+        addr
+    }
+
+    fn request(
+        addr: SocketAddr,
+        method: &str,
+        target: &str,
+        headers: &str,
+        body: &[u8],
+    ) -> (u16, ByteString) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nConnection: close\r\nContent-Length: {}\r\n{}\r\n",
+            method,
+            target,
+            body.len(),
+            headers
+        )
+        .unwrap();
+        stream.write_all(body).unwrap();
+
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        let status = std::str::from_utf8(&response[9..12])
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body_start = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        (status, response[body_start..].to_vec())
+    }
+
+    #[test]
+    fn serves_keys_over_http() {
+        let dir = tempfile::tempdir().unwrap();
+        let addr = start_server(ActionKV::open(&dir.path().join("store.akv")).unwrap());
+
+        assert_eq!(request(addr, "GET", "/kv/a%2Fb", "", b"").0, 404);
+        assert_eq!(request(addr, "PUT", "/kv/a%2Fb", "", b"\x00\xff").0, 204);
+        assert_eq!(
+            request(addr, "GET", "/kv/a%2Fb", "", b""),
+            (200, b"\x00\xff".to_vec())
+        );
+
+        let json = "Content-Type: application/json\r\n";
+        assert_eq!(
+            request(addr, "PUT", "/kv/json", json, br#"{ "value": "aGk=" }"#).0,
+            204
+        );
+        assert_eq!(
+            request(addr, "PUT", "/kv/json", json, br#"{"value": "!"}"#).0,
+            400
+        );
+        assert_eq!(
+            request(addr, "GET", "/kv/json", "Accept: application/json\r\n", b""),
+            (200, br#"{"key":"anNvbg==","value":"aGk="}"#.to_vec())
+        );
+        assert_eq!(
+            request(addr, "GET", "/kv?prefix=a%2F", "", b""),
+            (200, br#"[{"key":"YS9i","value":"AP8="}]"#.to_vec())
+        );
+        assert_eq!(
+            request(addr, "GET", "/kv", "", b"").1,
+            br#"[{"key":"YS9i","value":"AP8="},{"key":"anNvbg==","value":"aGk="}]"#
+        );
+
+        assert_eq!(
+            request(addr, "POST", "/kv/hits?incr=5", "", b""),
+            (200, br#"{"value":5}"#.to_vec())
+        );
+        assert_eq!(
+            request(addr, "POST", "/kv/hits?decr=7", "", b""),
+            (200, br#"{"value":-2}"#.to_vec())
+        );
+        assert_eq!(request(addr, "POST", "/kv/hits?incr=x", "", b"").0, 400);
+        assert_eq!(request(addr, "POST", "/kv/hits", "", b"").0, 400);
+        assert_eq!(request(addr, "POST", "/kv/json?incr=1", "", b"").0, 409);
+        assert_eq!(request(addr, "DELETE", "/kv/hits", "", b"").0, 204);
+
+        assert_eq!(request(addr, "DELETE", "/kv/json", "", b"").0, 204);
+        assert_eq!(request(addr, "DELETE", "/kv/json", "", b"").0, 404);
+        assert_eq!(request(addr, "PATCH", "/kv/json", "", b"").0, 405);
+        assert_eq!(request(addr, "GET", "/elsewhere", "", b"").0, 404);
+    }
+
+    #[test]
+    fn damaged_records_are_server_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"key", b"value").unwrap();
+        let addr = start_server(store);
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(crate::segment::path(&path, 1))
+            .unwrap();
+        file.seek(SeekFrom::End(-1)).unwrap();
+        file.write_all(b"V").unwrap();
+
+        let (status, body) = request(addr, "GET", "/kv/key", "", b"");
+        assert_eq!(status, 500);
+        assert!(String::from_utf8(body).unwrap().contains("corruption"));
+    }
+}
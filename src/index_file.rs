@@ -0,0 +1,275 @@
+//! Sidecar file holding a snapshot of the in-memory index.
+//!
+//! Layout: magic, the format version (`u16`), the log position the snapshot
+//! covers, the entry count,
+//! then `key_len | key | position | expires_at | merge` for every entry,
+//! followed by a CRC32 of all of the preceding bytes. Positions are a `u32`
+//! segment id and a `u64` offset. `expires_at` is zero for keys without a
+//! time-to-live. `merge` is the key's merge chain: a zero byte for keys that
+//! have none, otherwise one more than the number of operands, a flag byte
+//! for whether a base value follows, the base position if so, and the
+//! operand positions.
+//!
+//! The index file of an encrypted store has its own magic, and everything
+//! between that and the CRC is a nonce and tag followed by the encrypted
+//! remainder of the layout above, version included. Hint files are framed
+//! the same way.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, BufWriter, Read as _, Write},
+    path::Path,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
+
+use crate::{
+    crypto::{Cipher, NONCE_LEN, TAG_LEN},
+    merge::{MergeChain, Merges},
+    ByteString, Expiries, Position, CRC32,
+};
+
+/// The index, the expiry times, the merge chains and the log position that a
+/// file covers.
+type Contents = (BTreeMap<ByteString, Position>, Expiries, Merges, Position);
+
+/// Files written before expiry times were added start with `AKVI`, those
+/// from before key lengths were widened to `u64` with `AKVJ`, those from
+/// before merge chains were added with `AKVK`, or `AKVF` if encrypted, and
+/// those from before the version field was added with `AKVL` or `AKVM`; they
+/// fail this check and are rebuilt from the log. Later changes to the layout
+/// bump `VERSION` instead.
+const MAGIC: &[u8; 4] = b"AKVX";
+const ENCRYPTED_MAGIC: &[u8; 4] = b"AKVY";
+
+/// The layout written by this version of the crate. Files with any other
+/// version are ignored, and the index rebuilt from the log.
+const VERSION: u16 = 1;
+
+/// Atomically replaces the index file at `path`.
+pub(crate) fn write(
+    path: &Path,
+    tmp_path: &Path,
+    index: &BTreeMap<ByteString, Position>,
+    expiries: &Expiries,
+    merges: &Merges,
+    covered: Position,
+    cipher: Option<&Cipher>,
+) -> io::Result<()> {
+    let mut body = Vec::new();
+    body.write_u16::<LittleEndian>(VERSION)?;
+    write_position(&mut body, covered)?;
+    body.write_u64::<LittleEndian>(index.len() as u64)?;
+    for (key, position) in index {
+        body.write_u64::<LittleEndian>(key.len() as u64)?;
+        body.write_all(key)?;
+        write_position(&mut body, *position)?;
+        body.write_u64::<LittleEndian>(expiries.get(key).copied().unwrap_or(0))?;
+        match merges.get(key) {
+            Some(chain) => {
+                body.write_u64::<LittleEndian>(chain.operands.len() as u64 + 1)?;
+                body.write_u8(chain.base.is_some() as u8)?;
+                if let Some(base) = chain.base {
+                    write_position(&mut body, base)?;
+                }
+                for operand in &chain.operands {
+                    write_position(&mut body, *operand)?;
+                }
+            }
+            None => body.write_u64::<LittleEndian>(0)?,
+        }
+    }
+
+    write_framed(path, tmp_path, (MAGIC, ENCRYPTED_MAGIC), body, cipher)
+}
+
+/// Atomically replaces the file at `path` with `body`, preceded by the first
+/// of `magics`, or encrypted and preceded by the second if there is a
+/// `cipher`, and followed by a CRC32.
+pub(crate) fn write_framed(
+    path: &Path,
+    tmp_path: &Path,
+    (magic, encrypted_magic): (&[u8; 4], &[u8; 4]),
+    mut body: Vec<u8>,
+    cipher: Option<&Cipher>,
+) -> io::Result<()> {
+    let mut file = File::create(tmp_path)?;
+    {
+        let mut out = ChecksumWriter {
+            inner: BufWriter::new(&mut file),
+            digest: CRC32.digest(),
+        };
+
+        match cipher {
+            Some(cipher) => {
+                let nonce = Cipher::nonce()?;
+                let tag = cipher.seal(&nonce, encrypted_magic, &mut body)?;
+                out.write_all(encrypted_magic)?;
+                out.write_all(&nonce)?;
+                out.write_all(&tag)?;
+            }
+            None => out.write_all(magic)?,
+        }
+        out.write_all(&body)?;
+
+        let checksum = out.digest.finalize();
+        out.inner.write_u32::<LittleEndian>(checksum)?;
+        out.inner.flush()?;
+    }
+    file.sync_all()?;
+
+    fs::rename(tmp_path, path)
+}
+
+/// Reads the index file at `path`, returning the index, the expiry times and
+/// the log position it covers. A missing, truncated or corrupt file yields
+/// `None` so that the caller falls back to scanning the log, as does one
+/// that was not encrypted with `cipher` or has an unknown version.
+pub(crate) fn read(path: &Path, cipher: Option<&Cipher>) -> io::Result<Option<Contents>> {
+    let body = read_framed(path, (MAGIC, ENCRYPTED_MAGIC), cipher)?;
+    Ok(body.and_then(|body| parse(&body).ok()))
+}
+
+/// Reads the body of a file written by `write_framed` with the same `magics`
+/// and `cipher`. Returns `None` if the file is missing, or fails its checks.
+pub(crate) fn read_framed(
+    path: &Path,
+    (magic, encrypted_magic): (&[u8; 4], &[u8; 4]),
+    cipher: Option<&Cipher>,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut bytes = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut bytes)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    if bytes.len() < magic.len() + 4 {
+        return Ok(None);
+    }
+    let (body, mut trailer) = bytes.split_at(bytes.len() - 4);
+    let saved_checksum = trailer.read_u32::<LittleEndian>()?;
+    if CRC32.checksum(body) != saved_checksum {
+        return Ok(None);
+    }
+
+    let (found, body) = body.split_at(magic.len());
+    match cipher {
+        None if found == magic => Ok(Some(body.to_vec())),
+        Some(cipher) if found == encrypted_magic && body.len() >= NONCE_LEN + TAG_LEN => {
+            let (nonce, rest) = body.split_at(NONCE_LEN);
+            let (tag, encrypted) = rest.split_at(TAG_LEN);
+            let mut body = encrypted.to_vec();
+            let nonce = nonce.try_into().unwrap();
+            if !cipher.open(nonce, encrypted_magic, &mut body, tag.try_into().unwrap()) {
+                return Ok(None);
+            }
+            Ok(Some(body))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn parse(mut body: &[u8]) -> io::Result<Contents> {
+    if body.read_u16::<LittleEndian>()? != VERSION {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let covered = read_position(&mut body)?;
+    let len = body.read_u64::<LittleEndian>()?;
+
+    let mut index = BTreeMap::new();
+    let mut expiries = HashMap::new();
+    let mut merges = HashMap::new();
+    for _ in 0..len {
+        let key_len = body.read_u64::<LittleEndian>()?;
+        if key_len > body.len() as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut key = vec![0; key_len as usize];
+        body.read_exact(&mut key)?;
+        let position = read_position(&mut body)?;
+        let expires_at = body.read_u64::<LittleEndian>()?;
+        if expires_at != 0 {
+            expiries.insert(key.clone(), expires_at);
+        }
+        if let Some(operands) = body.read_u64::<LittleEndian>()?.checked_sub(1) {
+            let base = match body.read_u8()? {
+                0 => None,
+                _ => Some(read_position(&mut body)?),
+            };
+            // Each position takes 12 bytes.
+            if operands > body.len() as u64 / 12 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            let operands = (0..operands)
+                .map(|_| read_position(&mut body))
+                .collect::<io::Result<_>>()?;
+            merges.insert(key.clone(), MergeChain { base, operands });
+        }
+        index.insert(key, position);
+    }
+
+    Ok((index, expiries, merges, covered))
+}
+
+fn write_position(body: &mut Vec<u8>, position: Position) -> io::Result<()> {
+    body.write_u32::<LittleEndian>(position.segment)?;
+    body.write_u64::<LittleEndian>(position.offset)
+}
+
+fn read_position(body: &mut &[u8]) -> io::Result<Position> {
+    Ok(Position {
+        segment: body.read_u32::<LittleEndian>()?,
+        offset: body.read_u64::<LittleEndian>()?,
+    })
+}
+
+struct ChecksumWriter<'a, W> {
+    inner: W,
+    digest: crc::Digest<'a, u32>,
+}
+
+impl<W: Write> Write for ChecksumWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digest.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_current_version_is_read() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("index");
+        let tmp_path = dir.path().join("index.tmp");
+        let covered = Position {
+            segment: 3,
+            offset: 40,
+        };
+        let index = BTreeMap::from([(b"key".to_vec(), covered)]);
+        let expiries = HashMap::new();
+        let merges = HashMap::new();
+        write(&path, &tmp_path, &index, &expiries, &merges, covered, None).unwrap();
+        let (read_index, ..) = read(&path, None).unwrap().unwrap();
+        assert_eq!(read_index, index);
+
+        // The magic is not that of a segment's file header.
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_ne!(MAGIC, crate::file_header::MAGIC);
+
+        let mut body = (VERSION + 1).to_le_bytes().to_vec();
+        body.extend_from_slice(&bytes[MAGIC.len() + 2..bytes.len() - 4]);
+        write_framed(&path, &tmp_path, (MAGIC, ENCRYPTED_MAGIC), body, None).unwrap();
+        assert!(read(&path, None).unwrap().is_none());
+    }
+}
@@ -1,160 +1,3015 @@
 use std::{
-    collections::HashMap,
-    fs::{File, OpenOptions},
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    ffi::OsString,
+    fs::{self, File, OpenOptions, TryLockError},
     io::{self, BufReader, BufWriter, Read as _, Seek, SeekFrom, Write},
-    path::Path,
+    mem,
+    ops::{Bound, RangeBounds},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
+use byteorder::{ByteOrder as _, LittleEndian, WriteBytesExt as _};
+
+mod batch;
+mod compaction;
+mod compression;
+mod crypto;
+mod durability;
+mod error;
+mod file_header;
+mod handle;
+mod hint;
+pub mod http;
+mod index_file;
+mod merge;
+pub mod net;
+mod options;
+pub mod resp;
+mod scan;
+mod segment;
+mod snapshot;
+mod stream;
+mod transaction;
+mod upgrade;
+
+pub use batch::WriteBatch;
+pub use compression::Compression;
+pub use durability::Durability;
+pub use error::{ActionKVError, CompareAndSwapError, Result};
+pub use handle::ActionKVHandle;
+pub use merge::MergeOperator;
+pub use options::ActionKVOptions;
+pub use scan::Scan;
+pub use snapshot::Snapshot;
+pub use stream::{ValueReader, ValueWriter};
+pub use transaction::Transaction;
+
+use compaction::Manifest;
+use crypto::{Cipher, NONCE_LEN, TAG_LEN};
+use durability::BackgroundSync;
+use file_header::FileHeader;
+use hint::Hint;
+use merge::{MergeChain, Merges};
+use scan::RecordSource;
 
 type ByteString = Vec<u8>;
 type ByteStr = [u8];
 
+/// Expiry time of every key that was written with a time-to-live, in
+/// milliseconds since the Unix epoch.
+type Expiries = HashMap<ByteString, u64>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct KeyValuePair {
     pub key: ByteString,
     pub value: ByteString,
 }
 
+/// Where a record is in the log: the id of the segment file holding it, and
+/// its offset within that file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    pub segment: u32,
+    pub offset: u64,
+}
+
 #[derive(Debug)]
 pub struct ActionKV {
+    /// The directory holding the segments and the index file.
+    path: PathBuf,
+    /// The active segment, which records are appended to.
     file: File,
-    pub index: HashMap<ByteString, u64>,
+    /// Id of the active segment.
+    active: u32,
+    /// The closed segments, by id, which are only read from.
+    segments: BTreeMap<u32, File>,
+    /// Size after which the active segment is closed and a new one started.
+    segment_size: u64,
+    /// Hints for the records in the active segment, written out when it is
+    /// closed. `None` if its start was never read, as when it was loaded
+    /// from the index file.
+    active_hints: Option<Vec<Hint>>,
+    pub index: BTreeMap<ByteString, Position>,
+    expiries: Expiries,
+    /// The records holding the values of keys whose latest record is a merge
+    /// operand. `index` has the position of that operand.
+    merges: Merges,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    /// Bytes appended to the log since the index file was last written.
+    unindexed: u64,
+    index_checkpoint_interval: u64,
+    durability: Durability,
+    encoding: Encoding,
+    background_sync: Option<BackgroundSync>,
+    /// The lock file, held for as long as the store is open, or `None` if
+    /// it was opened by [`open_read_only`](Self::open_read_only) and can not
+    /// be written.
+    lock: Option<File>,
+    /// Bumped whenever compaction replaces segments, so that readers with
+    /// their own descriptors know to reopen them.
+    generation: u64,
 }
 
 const CRC32: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_CKSUM);
+
+/// Default number of log bytes after which the index file is rewritten.
+const DEFAULT_INDEX_CHECKPOINT_INTERVAL: u64 = 16 * 1024 * 1024;
+
+/// Default size after which the active segment is closed.
+const DEFAULT_SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Size of the start of every record header: checksum and kind. The key
+/// and value lengths follow.
+const PREFIX_LEN: u64 = 5;
+
+/// Longest encoding of a length as a varint.
+const MAX_VARINT_LEN: u64 = 10;
+
+/// How much of a record's data is allocated up front. A damaged length can
+/// not claim more memory than this before the read runs out of log.
+const MAX_PREALLOCATION: u64 = 16 * 1024 * 1024;
+
+/// Size of the expiry timestamp that follows the lengths in
+/// [`RecordKind::ExpiringValue`] records.
+const EXPIRY_LEN: u64 = 8;
+
+/// Size of the longest record header: the checksum and kind, two lengths, an
+/// expiry time, and the nonce and tag of an encrypted record.
+const MAX_HEADER_LEN: u64 =
+    PREFIX_LEN + 2 * MAX_VARINT_LEN + EXPIRY_LEN + (NONCE_LEN + TAG_LEN) as u64;
+
+/// The kind byte of a record holds its [`RecordKind`] in the low bits, a
+/// flag for encryption, and the id of the [`Compression`] used for its value
+/// in the top bits. Readers that predate compression or encryption see an
+/// unknown kind and fail rather than return the stored bytes as the value.
+const CODEC_SHIFT: u8 = 5;
+/// Set on records whose key and value are encrypted. The nonce and tag
+/// follow the rest of the header.
+const ENCRYPTED_FLAG: u8 = 1 << 4;
+const KIND_MASK: u8 = ENCRYPTED_FLAG - 1;
+
+/// Tag stored in every record header, covered by the record's checksum.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum RecordKind {
+    /// The record holds the current value of its key.
+    Value = 0,
+    /// The key was deleted; the record carries no value.
+    Tombstone = 1,
+    /// The value holds the records of a [`WriteBatch`], which are applied
+    /// together.
+    Batch = 2,
+    /// Like `Value`, but the header carries the time at which the key
+    /// expires. Decoded as a `Value` with `expires_at` set.
+    ExpiringValue = 3,
+    /// The value is an operand for the store's [`MergeOperator`], to be
+    /// folded into the key's previous value.
+    Merge = 4,
+}
+
+impl RecordKind {
+    fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(RecordKind::Value),
+            1 => Some(RecordKind::Tombstone),
+            2 => Some(RecordKind::Batch),
+            3 => Some(RecordKind::ExpiringValue),
+            4 => Some(RecordKind::Merge),
+            _ => None,
+        }
+    }
+}
+
+/// What [`ActionKV::replay`] does about a damaged record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Recovery {
+    /// Fail with the error describing the damage.
+    None,
+    /// Truncate a torn final record of the active segment, as a crash part
+    /// way through a write leaves behind, and fail with
+    /// [`ActionKVError::DamagedSegment`] on damage anywhere else.
+    TornTail,
+    /// Truncate every segment at its first damaged record, discarding
+    /// whatever follows it.
+    Truncate,
+}
+
+/// How the key and value lengths in record headers are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum LengthFormat {
+    /// A `u32` each, as in logs of format version 1 and those from before
+    /// file headers were added.
+    Fixed32,
+    /// LEB128 varints, from format version 2.
+    #[default]
+    Varint,
+}
+
+impl LengthFormat {
+    /// The longest key or value the format can describe.
+    fn max(self) -> u64 {
+        match self {
+            LengthFormat::Fixed32 => u32::MAX as u64,
+            LengthFormat::Varint => u64::MAX,
+        }
+    }
+}
+
+/// How records are transformed on their way to and from the log, and the
+/// limits they are held to.
+#[derive(Debug, Clone)]
+struct Encoding {
+    compression: Compression,
+    cipher: Option<Cipher>,
+    lengths: LengthFormat,
+    max_key_len: u64,
+    max_value_len: u64,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding {
+            compression: Compression::None,
+            cipher: None,
+            lengths: LengthFormat::default(),
+            max_key_len: u64::MAX,
+            max_value_len: u64::MAX,
+        }
+    }
+}
+
+impl Encoding {
+    /// This encoding with lengths in the current format, for rewriting a log.
+    fn current(&self) -> Encoding {
+        Encoding {
+            lengths: LengthFormat::default(),
+            ..self.clone()
+        }
+    }
+}
+
+/// The header of a record in the log, up to the start of its key.
+struct RecordHeader {
+    saved_checksum: u32,
+    /// The kind, with `ExpiringValue` decoded as `Value`.
+    kind: RecordKind,
+    codec: Compression,
+    key_len: u64,
+    /// Length of the value as stored, after any compression.
+    val_len: u64,
+    expires_at: Option<u64>,
+    /// The header fields after the checksum, which encryption authenticates
+    /// as associated data.
+    aad: ByteString,
+    /// The nonce and tag of an encrypted record.
+    sealed: Option<[u8; NONCE_LEN + TAG_LEN]>,
+}
+
+impl RecordHeader {
+    /// Length of the header in the log.
+    fn len(&self) -> u64 {
+        let sealed_len = self.sealed.map_or(0, |sealed| sealed.len());
+        4 + (self.aad.len() + sealed_len) as u64
+    }
+
+    fn data_len(&self, offset: u64) -> Result<u64> {
+        self.key_len
+            .checked_add(self.val_len)
+            .ok_or_else(|| ActionKVError::BadHeader {
+                offset,
+                reason: "key and value lengths overflow".to_string(),
+            })
+    }
+
+    /// Starts the checksum of the record, which continues over its data.
+    fn digest(&self) -> crc::Digest<'static, u32> {
+        let mut digest = CRC32.digest();
+        digest.update(&self.aad);
+        if let Some(sealed) = &self.sealed {
+            digest.update(sealed);
+        }
+        digest
+    }
+
+    fn check_checksum(&self, checksum: u32, offset: u64) -> Result<()> {
+        if checksum != self.saved_checksum {
+            return Err(ActionKVError::ChecksumMismatch {
+                offset,
+                expected: self.saved_checksum,
+                actual: checksum,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A record as decoded from the log, before it is applied to the index.
+struct Record {
+    kind: RecordKind,
+    key: ByteString,
+    value: ByteString,
+    /// Milliseconds since the Unix epoch after which the key is absent.
+    expires_at: Option<u64>,
+}
+
 impl ActionKV {
-    pub fn open(path: &Path) -> io::Result<Self> {
-        let file = OpenOptions::new()
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::options().open(path)
+    }
+
+    /// Opens the existing store at `path` for reading only, without taking
+    /// its lock, so that it can be read while another process writes to it.
+    /// Nothing on disk is changed, not even to clean up after a crash, and
+    /// every write fails with [`ActionKVError::ReadOnly`]. Call
+    /// [`load`](Self::load) to read the existing records.
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        Self::options().open_read_only(path)
+    }
+
+    /// Returns a builder for opening a store with non-default settings.
+    pub fn options() -> ActionKVOptions {
+        ActionKVOptions::new()
+    }
+
+    fn with_options(path: &Path, options: &ActionKVOptions, read_only: bool) -> Result<Self> {
+        if path.is_file() {
+            return Err(ActionKVError::UnsupportedFormat {
+                reason: "a single log file from before segments were added; \
+                         convert it with `akv_mem FILE upgrade`"
+                    .to_string(),
+            });
+        }
+        // What an interrupted compaction left behind is only cleaned up
+        // under the lock, which the one writer holds, so that the files of
+        // one still running are never mistaken for leftovers.
+        let lock = if read_only {
+            None
+        } else {
+            fs::create_dir_all(path)?;
+            let lock = Self::lock(path)?;
+            compaction::recover(path)?;
+            Some(lock)
+        };
+
+        let encoding = Self::encoding(options);
+        let mut ids = segment::list(path)?;
+        let active = ids.pop().unwrap_or(1);
+        let mut segments = BTreeMap::new();
+        for id in ids {
+            segments.insert(id, segment::open_closed(path, id, &encoding)?);
+        }
+        let (file, background_sync) = if read_only {
+            (segment::open_closed(path, active, &encoding)?, None)
+        } else {
+            let file = segment::open_active(path, active, &encoding)?;
+            let background_sync = BackgroundSync::start(options.durability, &file)?;
+            (file, background_sync)
+        };
+        let active_hints = (file.metadata()?.len() == file_header::LEN).then(Vec::new);
+
+        Ok(ActionKV {
+            path: path.to_path_buf(),
+            file,
+            active,
+            segments,
+            segment_size: options.segment_size,
+            active_hints,
+            index: BTreeMap::new(),
+            expiries: HashMap::new(),
+            merges: HashMap::new(),
+            merge_operator: options.merge_operator.clone(),
+            unindexed: 0,
+            index_checkpoint_interval: options.index_checkpoint_interval,
+            durability: options.durability,
+            encoding,
+            background_sync,
+            lock,
+            generation: 0,
+        })
+    }
+
+    fn encoding(options: &ActionKVOptions) -> Encoding {
+        Encoding {
+            compression: options.compression,
+            cipher: options.encryption_key.map(Cipher::new),
+            lengths: LengthFormat::default(),
+            max_key_len: options.max_key_len,
+            max_value_len: options.max_value_len,
+        }
+    }
+
+    /// Converts a store written by an earlier version to the current format,
+    /// returning `false` if it is already up to date. This includes single
+    /// log files from before segments, file headers, or records' kind byte
+    /// were added, which are replaced by a directory of the same name. Like
+    /// [`compact`](Self::compact), this keeps only the live records.
+    pub fn upgrade(path: &Path) -> Result<bool> {
+        Self::options().upgrade(path)
+    }
+
+    fn open_log(path: &Path) -> io::Result<File> {
+        OpenOptions::new()
             .read(true)
-            .write(true)
             .create(true)
             .append(true)
-            .open(path)?;
-        let index = HashMap::new();
-
-        Ok(ActionKV { file, index })
+            .open(path)
     }
 
-    fn process_record<R: io::Read>(file: &mut R) -> io::Result<KeyValuePair> {
-        let saved_checksum = file.read_u32::<LittleEndian>()?;
-        let key_len = file.read_u32::<LittleEndian>()?;
-        let val_len = file.read_u32::<LittleEndian>()?;
-        let data_len = key_len + val_len;
+    /// Decodes the record starting at `offset`, checking and removing its
+    /// encryption and compression. Returns `None` if `file` is already at its
+    /// end.
+    fn process_record<R: io::Read>(
+        file: &mut R,
+        offset: u64,
+        encoding: &Encoding,
+    ) -> Result<Option<Record>> {
+        let header = match Self::read_header(file, offset, encoding)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        let data_len = header.data_len(offset)?;
 
-        let mut data = ByteString::with_capacity(data_len as usize);
+        let mut data = ByteString::with_capacity(data_len.min(MAX_PREALLOCATION) as usize);
 
         {
-            file.by_ref().take(data_len as u64).read_to_end(&mut data)?;
+            file.by_ref().take(data_len).read_to_end(&mut data)?;
         }
 
-        debug_assert_eq!(data.len(), data_len as usize);
+        if (data.len() as u64) < data_len {
+            return Err(ActionKVError::TruncatedRecord { offset });
+        }
 
-        let checksum = CRC32.checksum(&data);
+        let mut digest = header.digest();
+        digest.update(&data);
+        header.check_checksum(digest.finalize(), offset)?;
 
-        if checksum != saved_checksum {
-            panic!(
-                "data corruption encountered ({:08x} != {:08x})",
-                checksum, saved_checksum
-            )
+        if let Some(sealed) = &header.sealed {
+            let cipher = encoding
+                .cipher
+                .as_ref()
+                .ok_or(ActionKVError::KeyRequired { offset })?;
+            let (nonce, tag) = sealed.split_at(NONCE_LEN);
+            if !cipher.open(
+                nonce.try_into().unwrap(),
+                &header.aad,
+                &mut data,
+                tag.try_into().unwrap(),
+            ) {
+                return Err(ActionKVError::WrongKey { offset });
+            }
         }
 
-        let value = data.split_off(key_len as usize);
+        let mut value = data.split_off(header.key_len as usize);
         let key = data;
+        let codec = header.codec;
+        if codec != Compression::None {
+            value = codec
+                .decompress(&value)
+                .ok_or_else(|| ActionKVError::BadValue {
+                    offset,
+                    reason: format!("value is not valid {:?} data", codec),
+                })?;
+            Self::check_lens(header.key_len, value.len() as u64, encoding, Some(offset))?;
+        }
+
+        Ok(Some(Record {
+            kind: header.kind,
+            key,
+            value,
+            expires_at: header.expires_at,
+        }))
+    }
+
+    /// Reads the header of the record starting at `offset`, leaving `file` at
+    /// the start of its key. Returns `None` if `file` is already at its end.
+    fn read_header<R: io::Read>(
+        file: &mut R,
+        offset: u64,
+        encoding: &Encoding,
+    ) -> Result<Option<RecordHeader>> {
+        let mut prefix = [0u8; PREFIX_LEN as usize];
+        match Self::read_full(file, &mut prefix)? {
+            0 => return Ok(None),
+            n if n < prefix.len() => return Err(ActionKVError::TruncatedRecord { offset }),
+            _ => {}
+        }
+
+        let saved_checksum = LittleEndian::read_u32(&prefix);
+        let kind = prefix[4];
+        let mut aad = vec![kind];
+        let key_len = Self::read_len(file, encoding.lengths, &mut aad, offset)?;
+        let val_len = Self::read_len(file, encoding.lengths, &mut aad, offset)?;
+
+        let codec =
+            Compression::from_id(kind >> CODEC_SHIFT).ok_or_else(|| ActionKVError::BadHeader {
+                offset,
+                reason: format!("unknown compression codec {}", kind >> CODEC_SHIFT),
+            })?;
+        let encrypted = kind & ENCRYPTED_FLAG != 0;
+        let mut kind =
+            RecordKind::from_u8(kind & KIND_MASK).ok_or_else(|| ActionKVError::BadHeader {
+                offset,
+                reason: format!("unknown record kind {:#04x}", kind),
+            })?;
+        if kind != RecordKind::Batch {
+            Self::check_lens(key_len, val_len, encoding, Some(offset))?;
+        }
 
-        Ok(KeyValuePair { key, value })
+        let mut expires_at = None;
+        if kind == RecordKind::ExpiringValue {
+            let mut expiry = [0u8; EXPIRY_LEN as usize];
+            if Self::read_full(file, &mut expiry)? < expiry.len() {
+                return Err(ActionKVError::TruncatedRecord { offset });
+            }
+            kind = RecordKind::Value;
+            expires_at = Some(u64::from_le_bytes(expiry));
+            aad.extend_from_slice(&expiry);
+        }
+
+        let mut sealed = None;
+        if encrypted {
+            let mut nonce_and_tag = [0u8; NONCE_LEN + TAG_LEN];
+            if Self::read_full(file, &mut nonce_and_tag)? < nonce_and_tag.len() {
+                return Err(ActionKVError::TruncatedRecord { offset });
+            }
+            sealed = Some(nonce_and_tag);
+        }
+
+        Ok(Some(RecordHeader {
+            saved_checksum,
+            kind,
+            codec,
+            key_len,
+            val_len,
+            expires_at,
+            aad,
+            sealed,
+        }))
+    }
+
+    /// Reads a key or value length in the format `lengths`, appending its
+    /// bytes to `raw`.
+    fn read_len<R: io::Read>(
+        file: &mut R,
+        lengths: LengthFormat,
+        raw: &mut ByteString,
+        offset: u64,
+    ) -> Result<u64> {
+        match lengths {
+            LengthFormat::Fixed32 => {
+                let mut len = [0u8; 4];
+                if Self::read_full(file, &mut len)? < len.len() {
+                    return Err(ActionKVError::TruncatedRecord { offset });
+                }
+                raw.extend_from_slice(&len);
+                Ok(u32::from_le_bytes(len) as u64)
+            }
+            LengthFormat::Varint => {
+                let mut len = 0u64;
+                for i in 0..MAX_VARINT_LEN {
+                    let mut byte = [0u8];
+                    if Self::read_full(file, &mut byte)? == 0 {
+                        return Err(ActionKVError::TruncatedRecord { offset });
+                    }
+                    raw.push(byte[0]);
+                    let bits = (byte[0] & 0x7f) as u64;
+                    if i == MAX_VARINT_LEN - 1 && bits > 1 {
+                        break;
+                    }
+                    len |= bits << (7 * i);
+                    if byte[0] & 0x80 == 0 {
+                        return Ok(len);
+                    }
+                }
+                Err(ActionKVError::BadHeader {
+                    offset,
+                    reason: "malformed length".to_string(),
+                })
+            }
+        }
     }
 
-    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
-        let position = self.insert_but_ignore_index(key, value)?;
+    fn write_len(out: &mut ByteString, lengths: LengthFormat, mut len: u64) {
+        match lengths {
+            LengthFormat::Fixed32 => out.extend_from_slice(&(len as u32).to_le_bytes()),
+            LengthFormat::Varint => {
+                while len >= 0x80 {
+                    out.push(len as u8 | 0x80);
+                    len >>= 7;
+                }
+                out.push(len as u8);
+            }
+        }
+    }
 
-        self.index.insert(key.to_vec(), position);
+    /// Fails with [`ActionKVError::OversizeRecord`] if a key or value is
+    /// longer than `encoding` allows. `offset` is that of the record being
+    /// read, if any.
+    fn check_lens(
+        key_len: u64,
+        val_len: u64,
+        encoding: &Encoding,
+        offset: Option<u64>,
+    ) -> Result<()> {
+        for (len, max) in [
+            (key_len, encoding.max_key_len),
+            (val_len, encoding.max_value_len),
+        ] {
+            let max = max.min(encoding.lengths.max());
+            if len > max {
+                return Err(ActionKVError::OversizeRecord { offset, len, max });
+            }
+        }
         Ok(())
     }
 
-    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<()> {
+    /// Like `read_exact`, but reports how many bytes were read before the end
+    /// of the input instead of failing.
+    fn read_full<R: io::Read>(file: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match file.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(filled)
+    }
+
+    /// Encodes a single record onto `out` and returns the number of bytes
+    /// written. A `Value` with `expires_at` set is written as an
+    /// `ExpiringValue`. Values are compressed if that makes them smaller, and
+    /// every record but a batch, whose contents are already encrypted, is
+    /// encrypted if the store has a key.
+    fn write_record<W: Write>(
+        out: &mut W,
+        kind: RecordKind,
+        key: &ByteStr,
+        value: &ByteStr,
+        expires_at: Option<u64>,
+        encoding: &Encoding,
+    ) -> Result<u64> {
+        let key_len = key.len();
+        match kind {
+            // A batch's value is its records, each of which was checked.
+            RecordKind::Batch => {
+                let unlimited = Encoding {
+                    max_key_len: u64::MAX,
+                    max_value_len: u64::MAX,
+                    ..encoding.clone()
+                };
+                Self::check_lens(key_len as u64, value.len() as u64, &unlimited, None)?
+            }
+            _ => Self::check_lens(key_len as u64, value.len() as u64, encoding, None)?,
+        }
+
+        let compression = encoding.compression;
+        let (value, codec) = match kind {
+            RecordKind::Value => match compression.compress(value) {
+                Some(compressed) => (Cow::Owned(compressed), compression),
+                None => (Cow::Borrowed(value), Compression::None),
+            },
+            _ => (Cow::Borrowed(value), Compression::None),
+        };
+        let cipher = encoding
+            .cipher
+            .as_ref()
+            .filter(|_| kind != RecordKind::Batch);
+
+        let val_len = value.len();
+        let mut tmp = ByteString::with_capacity(MAX_HEADER_LEN as usize - 4 + key_len + val_len);
+
+        let kind = match (kind, expires_at) {
+            (RecordKind::Value, Some(_)) => RecordKind::ExpiringValue,
+            _ => kind,
+        };
+        let encrypted = if cipher.is_some() { ENCRYPTED_FLAG } else { 0 };
+        tmp.write_u8(kind as u8 | encrypted | codec.id() << CODEC_SHIFT)?;
+        Self::write_len(&mut tmp, encoding.lengths, key_len as u64);
+        Self::write_len(&mut tmp, encoding.lengths, val_len as u64);
+        if let Some(expires_at) = expires_at {
+            tmp.write_u64::<LittleEndian>(expires_at)?;
+        }
+
+        let aad_len = tmp.len();
+        if cipher.is_some() {
+            tmp.extend_from_slice(&[0; NONCE_LEN + TAG_LEN]);
+        }
+        tmp.extend_from_slice(key);
+        tmp.extend_from_slice(&value);
+
+        if let Some(cipher) = cipher {
+            let nonce = Cipher::nonce()?;
+            let (aad, rest) = tmp.split_at_mut(aad_len);
+            let (sealed, data) = rest.split_at_mut(NONCE_LEN + TAG_LEN);
+            let tag = cipher.seal(&nonce, aad, data)?;
+            sealed[..NONCE_LEN].copy_from_slice(&nonce);
+            sealed[NONCE_LEN..].copy_from_slice(&tag);
+        }
+
+        let checksum = CRC32.checksum(&tmp);
+
+        out.write_u32::<LittleEndian>(checksum)?;
+        out.write_all(&tmp)?;
+
+        Ok(4 + tmp.len() as u64)
+    }
+
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.put(key, value, None)
+    }
+
+    /// Like [`insert`](Self::insert), but `key` reads as absent once `ttl`
+    /// has passed, and is dropped by the next reload or compaction.
+    pub fn insert_with_ttl(&mut self, key: &ByteStr, value: &ByteStr, ttl: Duration) -> Result<()> {
+        self.put(key, value, Some(expiry_after(ttl)))
+    }
+
+    /// Writes a value that expires at `expires_at`, if that is set.
+    fn put(&mut self, key: &ByteStr, value: &ByteStr, expires_at: Option<u64>) -> Result<()> {
+        let (position, written) = self.append_record(RecordKind::Value, key, value, expires_at)?;
+
+        self.commit_record(RecordKind::Value, key, expires_at, position, written)
+    }
+
+    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
         self.insert(key, value)
     }
 
-    pub fn delete(&mut self, key:&ByteStr)->io::Result<()>{
-        self.insert(key, b"")
+    /// Appends `operand` to the log as a change to `key`'s value, which the
+    /// store's [`MergeOperator`] folds into the value whenever it is read,
+    /// and for good when it is compacted. Unlike a read followed by
+    /// [`insert`](Self::insert), this never reads or rewrites the value.
+    ///
+    /// The key keeps any time-to-live it was written with, unless that has
+    /// already run out: then the key counts as absent, and the operand is
+    /// folded into no value and never expires. Fails with
+    /// [`ActionKVError::NoMergeOperator`] if the store was opened without a
+    /// merge operator.
+    ///
+    /// ```no_run
+    /// # use libactionkv::{ActionKV, MergeOperator};
+    /// #[derive(Debug)]
+    /// struct Concat;
+    ///
+    /// impl MergeOperator for Concat {
+    ///     fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+    ///         [existing.unwrap_or_default(), operand].concat()
+    ///     }
+    /// }
+    ///
+    /// let mut store = ActionKV::options()
+    ///     .merge_operator(Concat)
+    ///     .open(std::path::Path::new("store.akv"))?;
+    /// store.merge(b"log", b"started;")?;
+    /// store.merge(b"log", b"stopped;")?;
+    /// assert_eq!(store.get(b"log")?, Some(b"started;stopped;".to_vec()));
+    /// # Ok::<(), libactionkv::ActionKVError>(())
+    /// ```
+    pub fn merge(&mut self, key: &ByteStr, operand: &ByteStr) -> Result<()> {
+        if self.merge_operator.is_none() {
+            return Err(ActionKVError::NoMergeOperator);
+        }
+        // A key whose time-to-live has run out is absent, so the operand
+        // starts a new chain, without the expired value or its expiry time.
+        // The tombstone records that in the log, so that replay, which can
+        // not tell when a merge was written, starts one too.
+        if self.is_expired(key, now_millis()) {
+            self.delete(key)?;
+        }
+        let (position, written) = self.append_record(RecordKind::Merge, key, operand, None)?;
+
+        self.commit_record(RecordKind::Merge, key, None, position, written)
+    }
+
+    pub fn delete(&mut self, key: &ByteStr) -> Result<()> {
+        let (position, written) = self.append_record(RecordKind::Tombstone, key, b"", None)?;
+
+        self.commit_record(RecordKind::Tombstone, key, None, position, written)
+    }
+
+    /// Sets `key` to `new`, or deletes it if `new` is `None`, but only if its
+    /// current value is `expected`, where `None` means that it is absent.
+    /// Otherwise nothing is written and the current value is returned in the
+    /// error. Like [`incr`](Self::incr), a swap keeps any time-to-live the
+    /// key was written with.
+    ///
+    /// [`ActionKVHandle::compare_and_swap`] does the same atomically with
+    /// respect to other writers through the handle.
+    ///
+    /// ```no_run
+    /// # use libactionkv::ActionKV;
+    /// # let mut store = ActionKV::open(std::path::Path::new("store.akv"))?;
+    /// match store.compare_and_swap(b"leader", None, Some(b"worker-1"))? {
+    ///     Ok(()) => println!("elected"),
+    ///     Err(err) => println!("following {:?}", err.current),
+    /// }
+    /// # Ok::<(), libactionkv::ActionKVError>(())
+    /// ```
+    pub fn compare_and_swap(
+        &mut self,
+        key: &ByteStr,
+        expected: Option<&ByteStr>,
+        new: Option<&ByteStr>,
+    ) -> Result<std::result::Result<(), CompareAndSwapError>> {
+        let current = self.get(key)?;
+        if current.as_deref() != expected {
+            return Ok(Err(CompareAndSwapError { current }));
+        }
+
+        match new {
+            Some(value) => {
+                let expires_at = current.and(self.expiries.get(key).copied());
+                self.put(key, value, expires_at)?
+            }
+            None if current.is_some() => self.delete(key)?,
+            None => {}
+        }
+        Ok(Ok(()))
+    }
+
+    /// Sets `key` to `value` unless it already has a value, which is then
+    /// returned instead.
+    pub fn insert_if_absent(
+        &mut self,
+        key: &ByteStr,
+        value: &ByteStr,
+    ) -> Result<Option<ByteString>> {
+        let swapped = self.compare_and_swap(key, None, Some(value))?;
+        Ok(swapped.err().and_then(|err| err.current))
+    }
+
+    /// Adds `delta` to the counter stored at `key` and returns its new value.
+    ///
+    /// Counters are stored as 8-byte little-endian `i64`s, and an absent key
+    /// counts as zero. Fails with [`ActionKVError::BadCounter`], writing
+    /// nothing, if `key` holds a value of another length or the result would
+    /// overflow. The key keeps any time-to-live it was written with.
+    ///
+    /// [`ActionKVHandle::incr`] does the same atomically with respect to
+    /// other writers through the handle.
+    ///
+    /// ```no_run
+    /// # use libactionkv::ActionKV;
+    /// # let mut store = ActionKV::open(std::path::Path::new("store.akv"))?;
+    /// assert_eq!(store.incr(b"visits", 1)?, 1);
+    /// assert_eq!(store.incr(b"visits", 10)?, 11);
+    /// assert_eq!(store.get(b"visits")?, Some(11i64.to_le_bytes().to_vec()));
+    /// # Ok::<(), libactionkv::ActionKVError>(())
+    /// ```
+    pub fn incr(&mut self, key: &ByteStr, delta: i64) -> Result<i64> {
+        self.add_to_counter(key, delta, i64::checked_add)
+    }
+
+    /// Subtracts `delta` from the counter stored at `key` and returns its new
+    /// value. See [`incr`](Self::incr).
+    pub fn decr(&mut self, key: &ByteStr, delta: i64) -> Result<i64> {
+        self.add_to_counter(key, delta, i64::checked_sub)
+    }
+
+    fn add_to_counter(
+        &mut self,
+        key: &ByteStr,
+        delta: i64,
+        op: fn(i64, i64) -> Option<i64>,
+    ) -> Result<i64> {
+        let current = self.get(key)?;
+        let next = counter_step(key, current.as_deref(), delta, op)?;
+        let expires_at = current.and(self.expiries.get(key).copied());
+        self.put(key, &next.to_le_bytes(), expires_at)?;
+        Ok(next)
+    }
+
+    /// Appends every operation in `batch` to the log as a single record, so
+    /// that they take effect together or, after a crash, not at all.
+    pub fn write_batch(&mut self, batch: &WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let (body, offsets) = batch.encode(&self.encoding)?;
+        let (position, written) = self.append_record(RecordKind::Batch, b"", &body, None)?;
+
+        let body_start = Position {
+            offset: position.offset + written - body.len() as u64,
+            ..position
+        };
+        self.commit_batch(batch, &offsets, body_start, body.len() as u64, written)
+    }
+
+    /// Runs `f` as a transaction, whose writes are appended to the log as a
+    /// single batch once it returns `Ok`. If it returns an error, nothing it
+    /// wrote is applied.
+    ///
+    /// The transaction is optimistic: it takes no locks while it runs, and
+    /// fails with [`ActionKVError::Conflict`] if another writer changed a key
+    /// that it read in the meantime. Here, `&mut self` keeps other writers
+    /// out, so that only happens with
+    /// [`ActionKVHandle::transaction`].
+    ///
+    /// ```no_run
+    /// # use libactionkv::ActionKV;
+    /// # let mut store = ActionKV::open(std::path::Path::new("store.akv"))?;
+    /// store.transaction(|txn| {
+    ///     let balance = txn.get(b"balance")?.unwrap_or_default();
+    ///     txn.insert(b"balance", &[balance.as_slice(), b"!"].concat());
+    ///     Ok(())
+    /// })?;
+    /// # Ok::<(), libactionkv::ActionKVError>(())
+    /// ```
+    pub fn transaction<T, F>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction<'_>) -> Result<T>,
+    {
+        let generation = self.generation;
+        let mut txn = Transaction::new(self, generation);
+        let result = f(&mut txn)?;
+        let commit = txn.finish();
+
+        commit.check(self)?;
+        self.write_batch(&commit.batch)?;
+        Ok(result)
+    }
+
+    /// Applies a record that `append_record` wrote at `position` to the index.
+    fn commit_record(
+        &mut self,
+        kind: RecordKind,
+        key: &ByteStr,
+        expires_at: Option<u64>,
+        position: Position,
+        written: u64,
+    ) -> Result<()> {
+        self.unindexed += written;
+        let record = Record {
+            kind,
+            key: key.to_vec(),
+            value: ByteString::new(),
+            expires_at,
+        };
+        if let Some(hints) = &mut self.active_hints {
+            hints.push(Hint::new(&record, position.offset, written));
+        }
+        Self::apply_record(
+            &mut self.index,
+            &mut self.expiries,
+            &mut self.merges,
+            record,
+            position,
+        );
+        self.maybe_roll_over()?;
+        self.maybe_save_index()
+    }
+
+    /// Applies a batch that `append_record` wrote to the index. `offsets` are
+    /// those returned by [`WriteBatch::encode`], and `body_start` and
+    /// `body_len` where the batch's value is in the log.
+    fn commit_batch(
+        &mut self,
+        batch: &WriteBatch,
+        offsets: &[u64],
+        body_start: Position,
+        body_len: u64,
+        written: u64,
+    ) -> Result<()> {
+        self.unindexed += written;
+        let lens = batch::record_lens(offsets, body_len);
+        for (((kind, key), offset), len) in batch.ops().zip(offsets).zip(lens) {
+            let record = Record {
+                kind,
+                key: key.to_vec(),
+                value: ByteString::new(),
+                expires_at: None,
+            };
+            let position = Position {
+                offset: body_start.offset + offset,
+                ..body_start
+            };
+            if let Some(hints) = &mut self.active_hints {
+                hints.push(Hint::new(&record, position.offset, len));
+            }
+            Self::apply_record(
+                &mut self.index,
+                &mut self.expiries,
+                &mut self.merges,
+                record,
+                position,
+            );
+        }
+        self.maybe_roll_over()?;
+        self.maybe_save_index()
     }
 
-    pub fn get(&mut self, key: &ByteStr) -> io::Result<Option<ByteString>> {
-        let position = match self.index.get(key) {
-            Some(position) => *position,
+    pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        let position = match self.live_position(key) {
+            Some(position) => position,
             None => return Ok(None),
         };
 
-        let kv = self.get_at(position)?;
+        let kv = self.read_value(key, position)?;
 
         Ok(Some(kv.value))
     }
 
-    pub fn get_at(&mut self, position: u64) -> io::Result<KeyValuePair> {
-        let mut buf = BufReader::new(&mut self.file);
-        buf.seek(SeekFrom::Start(position))?;
-        let kv = Self::process_record(&mut buf)?;
+    /// Like [`get`](Self::get), but returns a reader that fetches the value
+    /// from the log as it is read instead of loading it into memory. The
+    /// record's checksum is checked before the reader is returned, which
+    /// reads through the value once.
+    ///
+    /// Compressed and encrypted values, and those with merge operands to
+    /// fold in, can only be decoded whole, so the reader of one of those
+    /// holds it in memory.
+    ///
+    /// ```no_run
+    /// # use libactionkv::ActionKV;
+    /// # let store = ActionKV::open(std::path::Path::new("store.akv"))?;
+    /// if let Some(mut reader) = store.get_reader(b"video")? {
+    ///     std::io::copy(&mut reader, &mut std::io::stdout())?;
+    /// }
+    /// # Ok::<(), libactionkv::ActionKVError>(())
+    /// ```
+    pub fn get_reader(&self, key: &ByteStr) -> Result<Option<ValueReader<'_>>> {
+        match self.live_position(key) {
+            Some(position) if self.merges.contains_key(key) => {
+                let kv = self.read_value(key, position)?;
+                Ok(Some(ValueReader::in_memory(kv.value)))
+            }
+            Some(position) => ValueReader::open(self, position).map(Some),
+            None => Ok(None),
+        }
+    }
 
-        Ok(kv)
+    /// Starts writing a value of exactly `len` bytes to `key`, which is
+    /// appended to the log as it is written rather than first gathered in
+    /// memory. The write takes effect once [`ValueWriter::finish`] is
+    /// called; dropping the writer before then discards it.
+    ///
+    /// Values written this way are never compressed, but are encrypted if
+    /// the store has a key.
+    ///
+    /// ```no_run
+    /// # use libactionkv::ActionKV;
+    /// # let mut store = ActionKV::open(std::path::Path::new("store.akv"))?;
+    /// let mut file = std::fs::File::open("video.mp4")?;
+    /// let len = file.metadata()?.len();
+    /// let mut writer = store.insert_writer(b"video", len)?;
+    /// std::io::copy(&mut file, &mut writer)?;
+    /// writer.finish()?;
+    /// # Ok::<(), libactionkv::ActionKVError>(())
+    /// ```
+    pub fn insert_writer(&mut self, key: &ByteStr, len: u64) -> Result<ValueWriter<'_>> {
+        ValueWriter::new(self, key, len)
     }
 
-    pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> io::Result<u64> {
-        let mut buf = BufWriter::new(&mut self.file);
+    /// Where the next record will be appended.
+    fn end(&self) -> Result<Position> {
+        Ok(Position {
+            segment: self.active,
+            offset: self.file.metadata()?.len(),
+        })
+    }
 
-        let key_len = key.len();
-        let val_len = value.len();
-        let mut tmp = ByteString::with_capacity(key_len + val_len);
+    /// The position of the record holding `key`'s value, unless it is
+    /// absent or expired.
+    fn live_position(&self, key: &ByteStr) -> Option<Position> {
+        match self.index.get(key) {
+            Some(_) if self.is_expired(key, now_millis()) => None,
+            position => position.copied(),
+        }
+    }
+
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        self.index.contains_key(key) && !self.is_expired(key, now_millis())
+    }
+
+    /// Whether `key` was written with a time-to-live that ran out by `now`.
+    fn is_expired(&self, key: &ByteStr, now: u64) -> bool {
+        self.expiries
+            .get(key)
+            .is_some_and(|expires_at| *expires_at <= now)
+    }
+
+    pub fn get_at(&self, position: Position) -> Result<KeyValuePair> {
+        Self::read_record_at(
+            self.segment(position.segment)?,
+            position.offset,
+            &self.encoding,
+        )
+    }
+
+    /// The segment with id `id`, which fails if it is no longer in the
+    /// store's directory.
+    fn segment(&self, id: u32) -> Result<&File> {
+        if id == self.active {
+            return Ok(&self.file);
+        }
+        self.segments
+            .get(&id)
+            .ok_or(ActionKVError::MissingSegment { segment: id })
+    }
+
+    /// Returns the live key/value pairs whose keys fall within `range`, in key
+    /// order.
+    ///
+    /// ```no_run
+    /// # use libactionkv::ActionKV;
+    /// # let store = ActionKV::open(std::path::Path::new("store.akv"))?;
+    /// for kv in store.range(&b"a"[..]..&b"m"[..]) {
+    ///     let kv = kv?;
+    ///     println!("{:?} = {:?}", kv.key, kv.value);
+    /// }
+    /// # Ok::<(), libactionkv::ActionKVError>(())
+    /// ```
+    pub fn range<'a, R: RangeBounds<&'a ByteStr>>(&self, range: R) -> Scan<'_> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        self.scan_bounds(start, end)
+    }
+
+    /// Returns the live key/value pairs whose keys start with `prefix`, in
+    /// key order.
+    pub fn scan_prefix(&self, prefix: &ByteStr) -> Scan<'_> {
+        let (start, end) = scan::prefix_bounds(prefix);
+        self.scan_bounds(start, end.as_ref().map(Vec::as_slice))
+    }
+
+    fn scan_bounds(&self, start: Bound<&ByteStr>, end: Bound<&ByteStr>) -> Scan<'_> {
+        let keys = scan::index_range(&self.index, start, end);
+        Scan::new(self, keys, &self.expiries, now_millis())
+    }
+
+    /// Returns a read-only view of the store as it is now, which later
+    /// writes and compactions leave alone. Taking one copies the index.
+    ///
+    /// ```no_run
+    /// # use libactionkv::ActionKV;
+    /// # let mut store = ActionKV::open(std::path::Path::new("store.akv"))?;
+    /// let snapshot = store.snapshot()?;
+    /// store.insert(b"key", b"new")?;
+    /// for kv in snapshot.scan_prefix(b"") {
+    ///     let kv = kv?;
+    ///     println!("{:?} = {:?}", kv.key, kv.value);
+    /// }
+    /// # Ok::<(), libactionkv::ActionKVError>(())
+    /// ```
+    pub fn snapshot(&self) -> Result<Snapshot> {
+        Snapshot::new(self)
+    }
 
-        for byte in key {
-            tmp.push(*byte);
+    /// Reads the record at `position` with positional reads, leaving the
+    /// file's cursor alone so that any number of threads can read at once.
+    fn read_record_at(file: &File, position: u64, encoding: &Encoding) -> Result<KeyValuePair> {
+        let mut buf = BufReader::new(ReadAt {
+            file,
+            offset: position,
+        });
+        let record = Self::process_record(&mut buf, position, encoding)?
+            .ok_or(ActionKVError::TruncatedRecord { offset: position })?;
+
+        Ok(KeyValuePair {
+            key: record.key,
+            value: record.value,
+        })
+    }
+
+    pub fn insert_but_ignore_index(&mut self, key: &ByteStr, value: &ByteStr) -> Result<Position> {
+        let (position, written) = self.append_record(RecordKind::Value, key, value, None)?;
+
+        self.unindexed += written;
+        if let Some(hints) = &mut self.active_hints {
+            hints.push(Hint {
+                kind: RecordKind::Value,
+                key: key.to_vec(),
+                offset: position.offset,
+                len: written,
+                expires_at: None,
+            });
         }
+        Ok(position)
+    }
+
+    /// Appends a record to the log, returning its position and length. Only
+    /// needs `&self` so that readers can carry on while a writer appends; the
+    /// caller is responsible for there being a single writer at a time and
+    /// for applying the record to the index afterwards.
+    fn append_record(
+        &self,
+        kind: RecordKind,
+        key: &ByteStr,
+        value: &ByteStr,
+        expires_at: Option<u64>,
+    ) -> Result<(Position, u64)> {
+        self.check_writable()?;
+        let mut bytes = ByteString::new();
+        let written = Self::write_record(&mut bytes, kind, key, value, expires_at, &self.encoding)?;
+
+        let mut file = &self.file;
+        let offset = file.seek(SeekFrom::End(0))?;
+        file.write_all(&bytes)?;
+        self.appended(written)?;
+
+        let position = Position {
+            segment: self.active,
+            offset,
+        };
+        Ok((position, written))
+    }
 
-        for byte in value {
-            tmp.push(*byte);
+    /// Syncs, or counts towards the next sync, `written` bytes just appended
+    /// to the log, as the store's [`Durability`] requires.
+    fn appended(&self, written: u64) -> Result<()> {
+        match (&self.durability, &self.background_sync) {
+            (Durability::Always, _) => self.file.sync_data()?,
+            (_, Some(background_sync)) => background_sync.written(written)?,
+            _ => {}
         }
+        Ok(())
+    }
 
-        let checksum = CRC32.checksum(&tmp);
+    /// Forces every write made so far to stable storage, whatever the
+    /// configured [`Durability`]. Also reports any failure of an earlier
+    /// background sync.
+    pub fn sync(&self) -> Result<()> {
+        self.file.sync_data()?;
+        if let Some(background_sync) = &self.background_sync {
+            background_sync.synced()?;
+        }
+        Ok(())
+    }
+
+    fn maybe_roll_over(&mut self) -> Result<()> {
+        if self.file.metadata()?.len() >= self.segment_size {
+            self.roll_over()?;
+        }
+        Ok(())
+    }
+
+    /// Closes the active segment and starts the next, unless the active one
+    /// holds no records yet. The closed segment is synced first, as nothing
+    /// syncs it later, and its hint file written if its hints are known.
+    fn roll_over(&mut self) -> Result<()> {
+        self.check_writable()?;
+        let len = self.file.metadata()?.len();
+        if len <= file_header::LEN {
+            return Ok(());
+        }
+
+        self.file.sync_data()?;
+        if let Some(hints) = &self.active_hints {
+            hint::write(
+                &self.path,
+                self.active,
+                len,
+                hints,
+                self.encoding.cipher.as_ref(),
+            )?;
+        }
+        let next = self.active + 1;
+        let file = segment::open_active(&self.path, next, &self.encoding)?;
+        let closed = mem::replace(&mut self.file, file);
+        self.segments.insert(self.active, closed);
+        self.active = next;
+        self.active_hints = Some(Vec::new());
+        if let Some(background_sync) = &self.background_sync {
+            background_sync.replace_file(&self.file)?;
+        }
+        Ok(())
+    }
+
+    /// Opens the store at `path` and loads it like [`load`](Self::load), but
+    /// a torn record at the end of the active segment, as a crash part way
+    /// through a write leaves behind, is truncated instead of failing the
+    /// load.
+    ///
+    /// Returns the loaded store along with the number of bytes that were
+    /// discarded. A damaged record with more of the log after it is
+    /// corruption rather than a torn write, and fails with
+    /// [`ActionKVError::DamagedSegment`] without changing anything; see
+    /// [`open_and_repair`](Self::open_and_repair).
+    ///
+    /// Like every store not opened with
+    /// [`open_read_only`](Self::open_read_only), it holds an exclusive lock
+    /// on its directory until it is dropped, so that only one process at a
+    /// time writes to it: any other fails with [`ActionKVError::Locked`]
+    /// instead of recovering, or writing, while the first one does.
+    pub fn open_and_recover(path: &Path) -> Result<(Self, u64)> {
+        Self::options().open_and_recover(path)
+    }
+
+    /// Opens the store at `path` and loads it, truncating every segment at
+    /// its first damaged record wherever that is. Everything after the
+    /// damage is lost, including records that were intact, so this is only
+    /// for salvaging what comes before corruption that
+    /// [`open_and_recover`](Self::open_and_recover) refused to repair.
+    ///
+    /// Returns the loaded store along with the number of bytes that were
+    /// discarded. The store is locked like one opened with
+    /// [`open_and_recover`](Self::open_and_recover).
+    pub fn open_and_repair(path: &Path) -> Result<(Self, u64)> {
+        Self::options().open_and_repair(path)
+    }
+
+    /// Rebuilds `index` from the index file, if there is a usable one, and
+    /// then replays the part of the log written after it. Closed segments
+    /// are replayed from their hint files where those are usable, without
+    /// reading the segments themselves. Keys whose time-to-live has run out
+    /// are left out.
+    ///
+    /// A log that ends part way through a record is reported as
+    /// [`ActionKVError::TruncatedRecord`]; use
+    /// [`open_and_recover`](Self::open_and_recover) to repair it.
+    pub fn load(&mut self) -> Result<()> {
+        self.replay(Recovery::None).map(|_| ())
+    }
+
+    /// Shared implementation of `load`, `open_and_recover` and
+    /// `open_and_repair`. Damaged records are dealt with as `recovery` says,
+    /// and the number of bytes truncated is returned.
+    pub(crate) fn replay(&mut self, recovery: Recovery) -> Result<u64> {
+        let mut start = None;
+        if let Some((index, expiries, merges, covered)) =
+            index_file::read(&self.index_path(), self.encoding.cipher.as_ref())?
+        {
+            let usable = match self.segment(covered.segment) {
+                Ok(file) => covered.offset <= file.metadata()?.len(),
+                Err(_) => false,
+            };
+            if usable {
+                self.index = index;
+                self.expiries = expiries;
+                self.merges = merges;
+                start = Some(covered);
+            } else if self.lock.is_some() {
+                // It covers records that were lost, so once the log has
+                // grown past it again it would look usable while pointing at
+                // whatever was written in their place.
+                fs::remove_file(self.index_path())?;
+                Self::sync_parent_dir(&self.index_path())?;
+            }
+        }
+        if start.is_none() {
+            // Merge operands must not be applied twice.
+            self.index.clear();
+            self.expiries.clear();
+            self.merges.clear();
+        }
+
+        let ids: Vec<u32> = self
+            .segments
+            .keys()
+            .copied()
+            .chain([self.active])
+            .filter(|id| start.is_none_or(|start| *id >= start.segment))
+            .collect();
+        let mut replayed = 0;
+        let mut discarded = 0;
+
+        for id in ids {
+            let from = match start {
+                Some(start) if start.segment == id => start.offset,
+                _ => file_header::LEN,
+            };
+            let closed = id != self.active;
+            let file = if closed {
+                &self.segments[&id]
+            } else {
+                &self.file
+            };
+            let len = file.metadata()?.len();
+            let cipher = self.encoding.cipher.as_ref();
+
+            // A repair checks every record, so has no use for hints.
+            let hints = if closed && recovery != Recovery::Truncate {
+                hint::read(&self.path, id, len, cipher)?
+            } else {
+                None
+            };
+            if let Some(hints) = hints {
+                for hint in hints.into_iter().filter(|hint| hint.offset >= from) {
+                    let (position, record) = hint.into_record(id);
+                    Self::apply_record(
+                        &mut self.index,
+                        &mut self.expiries,
+                        &mut self.merges,
+                        record,
+                        position,
+                    );
+                }
+                replayed += len - from;
+                continue;
+            }
+
+            let mut hints = Vec::new();
+            let (end, damage) = Self::replay_segment(
+                file,
+                id,
+                from,
+                &self.encoding,
+                &mut self.index,
+                &mut self.expiries,
+                &mut self.merges,
+                &mut hints,
+                recovery,
+            )?;
+            replayed += end - from;
+
+            if let Some((err, torn)) = damage {
+                // Closed segments are synced before the next one is
+                // started, so only the active one can end in a torn write.
+                if recovery == Recovery::TornTail && (closed || !torn) {
+                    return Err(ActionKVError::DamagedSegment {
+                        segment: id,
+                        offset: end,
+                        after: len - end,
+                        reason: err.to_string(),
+                    });
+                }
+                discarded += len - end;
+                // Closed segments are opened read-only.
+                let file = OpenOptions::new()
+                    .write(true)
+                    .open(segment::path(&self.path, id))?;
+                file.set_len(end)?;
+                file.sync_all()?;
+            }
+
+            // Hints gathered part way through a segment would leave out the
+            // records before `from`.
+            let whole = from == file_header::LEN;
+            if closed && whole && self.lock.is_some() {
+                hint::write(&self.path, id, end, &hints, cipher)?;
+            } else if !closed {
+                self.active_hints = whole.then_some(hints);
+            }
+        }
 
-        let next_byte = SeekFrom::End(0);
-        let current_position = buf.seek(SeekFrom::Current(0))?;
-        buf.seek(next_byte)?;
+        let now = now_millis();
+        let expired: Vec<ByteString> = self
+            .expiries
+            .iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.index.remove(&key);
+            self.expiries.remove(&key);
+            self.merges.remove(&key);
+        }
 
-        buf.write_u32::<LittleEndian>(checksum)?;
-        buf.write_u32::<LittleEndian>(key_len as u32)?;
-        buf.write_u32::<LittleEndian>(val_len as u32)?;
-        buf.write_all(&tmp)?;
+        self.unindexed = replayed;
+        self.maybe_save_index()?;
 
-        Ok(current_position)
+        Ok(discarded)
     }
 
-    pub fn load(&mut self) -> io::Result<()> {
-        let mut buffer_from_file = BufReader::new(&mut self.file);
+    /// Applies the records of segment `id` from `start` onwards to `index`
+    /// and `expiries`, and appends hints for them to `hints`. Returns where
+    /// the last whole record ends and, unless `recovery` is
+    /// [`Recovery::None`], the damage that follows it rather than the end of
+    /// the segment, if any: the error describing it and whether it looks
+    /// like a torn final write.
+    #[allow(clippy::too_many_arguments)]
+    fn replay_segment(
+        file: &File,
+        id: u32,
+        start: u64,
+        encoding: &Encoding,
+        index: &mut BTreeMap<ByteString, Position>,
+        expiries: &mut Expiries,
+        merges: &mut Merges,
+        hints: &mut Vec<Hint>,
+        recovery: Recovery,
+    ) -> Result<(u64, Option<(ActionKVError, bool)>)> {
+        let mut buffer_from_file = BufReader::new(file);
+        buffer_from_file.seek(SeekFrom::Start(start))?;
+        let mut end = start;
 
         loop {
-            let position = buffer_from_file.seek(SeekFrom::Current(0))?;
+            let offset = buffer_from_file.stream_position()?;
 
-            let maybe_kv = Self::process_record(&mut buffer_from_file);
+            let maybe_record = Self::process_record(&mut buffer_from_file, offset, encoding);
 
-            let kv = match maybe_kv {
-                Ok(kv) => kv,
-                Err(err) => match err.kind() {
-                    io::ErrorKind::UnexpectedEof => {
-                        break;
-                    }
-                    _ => return Err(err),
-                },
+            let record = match maybe_record {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(err) if recovery != Recovery::None && err.is_corruption() => {
+                    let read_to = buffer_from_file.stream_position()?;
+                    let torn = Self::is_torn(file, offset, read_to, &err)?;
+                    return Ok((end, Some((err, torn))));
+                }
+                Err(err) => return Err(err),
             };
 
-            self.index.insert(kv.key, position);
+            let record_end = buffer_from_file.stream_position()?;
+            match record.kind {
+                RecordKind::Batch => {
+                    // Batch values are stored as they are, at the end of the
+                    // record.
+                    let value_start = record_end - record.value.len() as u64;
+                    let records = batch::decode(&record.value, value_start, encoding)?;
+                    let offsets: Vec<u64> = records.iter().map(|(offset, _)| *offset).collect();
+                    let lens = batch::record_lens(&offsets, record_end);
+                    for ((offset, record), len) in records.into_iter().zip(lens) {
+                        hints.push(Hint::new(&record, offset, len));
+                        let position = Position {
+                            segment: id,
+                            offset,
+                        };
+                        Self::apply_record(index, expiries, merges, record, position);
+                    }
+                }
+                _ => {
+                    hints.push(Hint::new(&record, offset, record_end - offset));
+                    let position = Position {
+                        segment: id,
+                        offset,
+                    };
+                    Self::apply_record(index, expiries, merges, record, position)
+                }
+            }
+            end = record_end;
         }
 
-        Ok(())
+        Ok((end, None))
     }
-}
 
-#[cfg(test)]
-mod tests {
+    /// Whether `err`, the damage found in the record at `offset` after
+    /// reading up to `read_to`, is what a crash part way through appending
+    /// the final record leaves: a record cut short, one whose checksum does
+    /// not match but which runs to the end of the segment, or a tail of
+    /// zeros where the file grew before its data was written.
+    fn is_torn(file: &File, offset: u64, read_to: u64, err: &ActionKVError) -> Result<bool> {
+        let len = file.metadata()?.len();
+        let final_record = match err {
+            ActionKVError::TruncatedRecord { .. } => true,
+            ActionKVError::ChecksumMismatch { .. } => read_to >= len,
+            _ => false,
+        };
+        if final_record {
+            return Ok(true);
+        }
 
-    #[test]
-    fn it_works() {}
+        let mut rest = BufReader::new(ReadAt { file, offset });
+        let mut buf = [0u8; 8192];
+        loop {
+            match rest.read(&mut buf)? {
+                0 => return Ok(true),
+                n if buf[..n].iter().any(|byte| *byte != 0) => return Ok(false),
+                _ => {}
+            }
+        }
+    }
+
+    fn apply_record(
+        index: &mut BTreeMap<ByteString, Position>,
+        expiries: &mut Expiries,
+        merges: &mut Merges,
+        record: Record,
+        position: Position,
+    ) {
+        match record.kind {
+            RecordKind::Value => {
+                match record.expires_at {
+                    Some(expires_at) => expiries.insert(record.key.clone(), expires_at),
+                    None => expiries.remove(&record.key),
+                };
+                merges.remove(&record.key);
+                index.insert(record.key, position);
+            }
+            RecordKind::Tombstone => {
+                expiries.remove(&record.key);
+                merges.remove(&record.key);
+                index.remove(&record.key);
+            }
+            // A live key keeps any time-to-live its value had. One that had
+            // run out when the operand was written is preceded by a
+            // tombstone, so its chain starts with no base.
+            RecordKind::Merge => {
+                let base = index.get(&record.key).copied();
+                merges
+                    .entry(record.key.clone())
+                    .or_insert_with(|| MergeChain {
+                        base,
+                        operands: Vec::new(),
+                    })
+                    .operands
+                    .push(position);
+                index.insert(record.key, position);
+            }
+            // Batches are expanded into their records by the caller, and
+            // expiring values are decoded as values.
+            RecordKind::Batch | RecordKind::ExpiringValue => {}
+        }
+    }
+
+    /// Writes the current index to the index file, next to the segments.
+    /// The log is synced first, whatever the store's [`Durability`], so that
+    /// the index file never covers records that a crash could still lose.
+    pub fn save_index(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.file.sync_data()?;
+        let covered = self.end()?;
+        index_file::write(
+            &self.index_path(),
+            &self.path.join("index.tmp"),
+            &self.index,
+            &self.expiries,
+            &self.merges,
+            covered,
+            self.encoding.cipher.as_ref(),
+        )?;
+
+        self.unindexed = 0;
+        Ok(())
+    }
+
+    fn maybe_save_index(&mut self) -> Result<()> {
+        if self.lock.is_some() && self.unindexed >= self.index_checkpoint_interval {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.path.join("index")
+    }
+
+    /// Rewrites the closed segments so that they only hold the latest value
+    /// of every key in `index`, dropping overwritten records, tombstones and
+    /// expired keys, and folding merge operands into the values they apply
+    /// to. Values are recompressed with the store's current
+    /// [`Compression`], and encrypted with its current key if it has one.
+    ///
+    /// The active segment is closed first, so everything written so far is
+    /// compacted. The compacted segments are written under fresh ids after
+    /// every existing segment, and later writes go to a new active segment
+    /// after them. They only replace the old segments once a marker file
+    /// listing both has been synced, so an interruption before that leaves
+    /// the store as it was, and one after it is finished on the next open.
+    ///
+    pub fn compact(&mut self) -> Result<()> {
+        self.check_writable()?;
+        self.roll_over()?;
+        let compacted = self.write_compacted()?;
+        self.install_compacted(compacted)
+    }
+
+    /// First half of `compact`: writes the live records held in closed
+    /// segments to new segment files, and works out the index and expiries
+    /// for them. Only reads from the store, so readers can carry on
+    /// meanwhile, but no writes may happen until `install_compacted` is
+    /// called.
+    ///
+    /// The active segment must hold no records, as `compact` makes sure by
+    /// rolling over first: the new segments take the ids after it.
+    fn write_compacted(&self) -> Result<Compacted> {
+        let encoding = self.encoding.current();
+
+        let mut compacted = Compacted {
+            segments: Vec::new(),
+            index: BTreeMap::new(),
+            expiries: HashMap::new(),
+            merges: HashMap::new(),
+        };
+        let mut out: Option<BufWriter<File>> = None;
+        let now = now_millis();
+
+        for (key, old_position) in self.index.iter() {
+            let expires_at = self.expiries.get(key).copied();
+            let chain = self.merges.get(key);
+            if self.is_expired(key, now) {
+                continue;
+            }
+
+            let full = compacted
+                .segments
+                .last()
+                .is_none_or(|segment| segment.len >= self.segment_size);
+            if full {
+                if let Some(buf) = out.take() {
+                    Self::finish_segment(buf)?;
+                }
+                let id = self.active + 1 + compacted.segments.len() as u32;
+                out = Some(Self::start_segment(
+                    &self.path,
+                    id,
+                    &encoding,
+                    &mut compacted,
+                )?);
+            }
+            let (buf, segment) = out
+                .as_mut()
+                .zip(compacted.segments.last_mut())
+                .expect("a segment was just started");
+
+            let value = match chain {
+                Some(chain) => {
+                    let operator = self.merge_operator.as_deref();
+                    merge::fold(self, operator, key, chain)?
+                }
+                None => self.get_at(*old_position)?.value,
+            };
+            let written =
+                Self::write_record(buf, RecordKind::Value, key, &value, expires_at, &encoding)?;
+
+            let position = Position {
+                segment: segment.id,
+                offset: segment.len,
+            };
+            segment.hints.push(Hint {
+                kind: RecordKind::Value,
+                key: key.clone(),
+                offset: segment.len,
+                len: written,
+                expires_at,
+            });
+            compacted.index.insert(key.clone(), position);
+            if let Some(expires_at) = expires_at {
+                compacted.expiries.insert(key.clone(), expires_at);
+            }
+            segment.len += written;
+        }
+
+        if let Some(buf) = out {
+            Self::finish_segment(buf)?;
+        }
+
+        Ok(compacted)
+    }
+
+    /// Creates the temporary file for the compacted segment that will take
+    /// id `id`, and writes its header.
+    fn start_segment(
+        dir: &Path,
+        id: u32,
+        encoding: &Encoding,
+        compacted: &mut Compacted,
+    ) -> Result<BufWriter<File>> {
+        let tmp_path = compaction::tmp_path(dir, id);
+        let tmp = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp_path)?;
+        compacted.segments.push(CompactedSegment {
+            id,
+            len: file_header::LEN,
+            hints: Vec::new(),
+        });
+
+        let mut buf = BufWriter::new(tmp);
+        buf.write_all(&FileHeader::new(encoding)?.encode())?;
+        Ok(buf)
+    }
+
+    fn finish_segment(buf: BufWriter<File>) -> io::Result<()> {
+        buf.into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()
+    }
+
+    /// Second half of `compact`: swaps the segments written by
+    /// `write_compacted` in for the closed segments, and starts a new active
+    /// segment after them.
+    fn install_compacted(&mut self, compacted: Compacted) -> Result<()> {
+        let manifest = compacted.manifest(self);
+        compaction::commit(&self.path, &manifest)?;
+
+        let encoding = self.encoding.current();
+        let active = match manifest.installed.last() {
+            Some(last) => {
+                let id = last + 1;
+                Some((id, segment::open_active(&self.path, id, &encoding)?))
+            }
+            None => None,
+        };
+        compaction::finish(&self.path, &manifest)?;
+
+        let mut segments = BTreeMap::new();
+        for segment in &compacted.segments {
+            segments.insert(
+                segment.id,
+                File::open(segment::path(&self.path, segment.id))?,
+            );
+        }
+
+        let cipher = encoding.cipher.as_ref();
+        for segment in &compacted.segments {
+            hint::write(&self.path, segment.id, segment.len, &segment.hints, cipher)?;
+        }
+
+        if let Some((id, file)) = active {
+            self.file = file;
+            self.active = id;
+            self.active_hints = Some(Vec::new());
+            if let Some(background_sync) = &self.background_sync {
+                background_sync.replace_file(&self.file)?;
+            }
+        }
+        self.segments = segments;
+        self.index = compacted.index;
+        self.expiries = compacted.expiries;
+        self.merges = compacted.merges;
+        self.encoding = encoding;
+        self.generation += 1;
+
+        self.save_index()
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Takes an exclusive advisory lock on the store in `dir`, which lasts
+    /// until the returned file is closed.
+    fn lock(dir: &Path) -> Result<File> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join("lock"))?;
+        match file.try_lock() {
+            Ok(()) => Ok(file),
+            Err(TryLockError::WouldBlock) => Err(ActionKVError::Locked),
+            Err(TryLockError::Error(err)) => Err(err.into()),
+        }
+    }
+
+    /// Fails with [`ActionKVError::ReadOnly`] if the store was opened by
+    /// [`open_read_only`](Self::open_read_only).
+    fn check_writable(&self) -> Result<()> {
+        match self.lock {
+            Some(_) => Ok(()),
+            None => Err(ActionKVError::ReadOnly),
+        }
+    }
+
+    /// Returns `path` with `suffix` appended to its file name.
+    fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+        let mut name = OsString::from(path.as_os_str());
+        name.push(suffix);
+        PathBuf::from(name)
+    }
+
+    /// Makes a rename or removal in the directory holding `path` durable.
+    #[cfg(unix)]
+    fn sync_parent_dir(path: &Path) -> io::Result<()> {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()
+    }
+
+    #[cfg(not(unix))]
+    fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Segments written by [`ActionKV::write_compacted`], along with the index
+/// and expiries to use once they are installed.
+struct Compacted {
+    segments: Vec<CompactedSegment>,
+    index: BTreeMap<ByteString, Position>,
+    expiries: Expiries,
+    merges: Merges,
+}
+
+impl Compacted {
+    /// The segments that installing these replaces: all of `store`'s closed
+    /// segments, and its empty active segment too if a new one is started
+    /// after the compacted ones.
+    fn manifest(&self, store: &ActionKV) -> Manifest {
+        let installed: Vec<u32> = self.segments.iter().map(|segment| segment.id).collect();
+        let mut removed: Vec<u32> = store.segments.keys().copied().collect();
+        if !installed.is_empty() {
+            removed.push(store.active);
+        }
+        Manifest { installed, removed }
+    }
+}
+
+/// A segment written by [`ActionKV::write_compacted`].
+struct CompactedSegment {
+    /// The id the segment is installed under.
+    id: u32,
+    len: u64,
+    hints: Vec<Hint>,
+}
+
+/// Milliseconds since the Unix epoch, the unit of record expiry times.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_millis() as u64)
+}
+
+/// The expiry time for a key written now with a time-to-live of `ttl`.
+fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis().min(u64::MAX as u128) as u64)
+}
+
+/// Applies `op` to the counter `current`, the value of `key`, and `delta`.
+fn counter_step(
+    key: &ByteStr,
+    current: Option<&ByteStr>,
+    delta: i64,
+    op: fn(i64, i64) -> Option<i64>,
+) -> Result<i64> {
+    let bad_counter = |reason: &str| ActionKVError::BadCounter {
+        key: key.to_vec(),
+        reason: reason.to_string(),
+    };
+    let count = match current.map(<[u8; 8]>::try_from) {
+        None => 0,
+        Some(Ok(bytes)) => i64::from_le_bytes(bytes),
+        Some(Err(_)) => return Err(bad_counter("the value is not an 8-byte integer")),
+    };
+    op(count, delta).ok_or_else(|| bad_counter("the result is out of range"))
+}
+
+/// Reader over a file that uses positional reads instead of the file's
+/// cursor.
+struct ReadAt<'a> {
+    file: &'a File,
+    offset: u64,
+}
+
+impl io::Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let read = std::os::unix::fs::FileExt::read_at(self.file, buf, self.offset)?;
+        #[cfg(windows)]
+        let read = std::os::windows::fs::FileExt::seek_read(self.file, buf, self.offset)?;
+
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn it_works() {}
+
+    /// Length of a value record in a new, unencrypted log.
+    fn record_len(key: &ByteStr, value: &ByteStr) -> u64 {
+        let encoding = Encoding::default();
+        ActionKV::write_record(
+            &mut Vec::new(),
+            RecordKind::Value,
+            key,
+            value,
+            None,
+            &encoding,
+        )
+        .unwrap()
+    }
+
+    /// Total size of the segments of the store at `path`.
+    fn log_len(path: &Path) -> u64 {
+        segment::list(path)
+            .unwrap()
+            .into_iter()
+            .map(|id| fs::metadata(segment::path(path, id)).unwrap().len())
+            .sum()
+    }
+
+    #[test]
+    fn compact_keeps_only_live_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open(&path).unwrap();
+        for i in 0..100u32 {
+            store.insert(b"counter", &i.to_le_bytes()).unwrap();
+        }
+        store.insert(b"name", b"actionkv").unwrap();
+        let before = log_len(&path);
+
+        store.compact().unwrap();
+
+        let after = log_len(&path);
+        assert!(after < before);
+        assert_eq!(
+            store.get(b"counter").unwrap(),
+            Some(99u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(store.get(b"name").unwrap(), Some(b"actionkv".to_vec()));
+
+        store.insert(b"name", b"compacted").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+        assert_eq!(store.get(b"name").unwrap(), Some(b"compacted".to_vec()));
+        assert_eq!(segment::list(&path).unwrap(), [3, 4]);
+    }
+
+    #[test]
+    fn log_rolls_over_into_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut options = ActionKV::options();
+        options.segment_size(64);
+
+        let mut store = options.open(&path).unwrap();
+        for i in 0..100u32 {
+            let key = format!("key:{}", i % 10);
+            store.insert(key.as_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        assert!(segment::list(&path).unwrap().len() > 10);
+        let segments: HashSet<u32> = store.index.values().map(|p| p.segment).collect();
+        assert!(segments.len() > 1);
+        store.save_index().unwrap();
+        store.insert(b"key:0", b"after").unwrap();
+        drop(store);
+
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"key:0").unwrap(), Some(b"after".to_vec()));
+        assert_eq!(
+            store.get(b"key:5").unwrap(),
+            Some(95u32.to_le_bytes().to_vec())
+        );
+
+        // The compacted segments take fresh ids after every old segment,
+        // ahead of a new active segment.
+        let old_active = store.active;
+        store.compact().unwrap();
+        let ids = segment::list(&path).unwrap();
+        assert!(ids.len() < 10);
+        assert_eq!(ids[0], old_active + 2);
+        assert_eq!(ids, (ids[0]..=store.active).collect::<Vec<_>>());
+        assert!(store.index.values().all(|p| p.segment < store.active));
+        store.insert(b"key:1", b"new").unwrap();
+        drop(store);
+
+        fs::remove_file(path.join("index")).unwrap();
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 10);
+        assert_eq!(store.get(b"key:1").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(
+            store.get(b"key:9").unwrap(),
+            Some(99u32.to_le_bytes().to_vec())
+        );
+        store.save_index().unwrap();
+        drop(store);
+
+        // Archiving a closed segment leaves the rest of the store readable.
+        fs::remove_file(segment::path(&path, ids[0])).unwrap();
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"key:1").unwrap(), Some(b"new".to_vec()));
+        assert!(matches!(
+            store.get(b"key:0"),
+            Err(ActionKVError::MissingSegment { segment }) if segment == ids[0]
+        ));
+    }
+
+    #[test]
+    fn closed_segments_load_from_hint_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut options = ActionKV::options();
+        options.segment_size(64);
+        let hint_files = |path: &Path| {
+            segment::list(path)
+                .unwrap()
+                .into_iter()
+                .filter(|id| hint::path(path, *id).exists())
+                .collect::<Vec<_>>()
+        };
+
+        let mut store = options.open(&path).unwrap();
+        for i in 0..20u32 {
+            let key = format!("key:{}", i);
+            store.insert(key.as_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        let mut batch = WriteBatch::new();
+        batch.put(b"batched", b"value").delete(b"key:3");
+        store.write_batch(&batch).unwrap();
+        store
+            .insert_with_ttl(b"session", b"token", Duration::from_secs(3600))
+            .unwrap();
+        store.insert(b"last", b"write").unwrap();
+        let active = store.active;
+        let closed: Vec<u32> = store.segments.keys().copied().collect();
+        assert_eq!(hint_files(&path), closed);
+        drop(store);
+
+        // No index file has been written yet, and loading from the hints
+        // gives the same result as reading every segment.
+        assert!(!path.join("index").exists());
+        let mut from_hints = options.open_read_only(&path).unwrap();
+        from_hints.load().unwrap();
+        for id in &closed {
+            fs::remove_file(hint::path(&path, *id)).unwrap();
+        }
+        let mut from_log = options.open(&path).unwrap();
+        from_log.load().unwrap();
+        assert_eq!(from_hints.index, from_log.index);
+        assert_eq!(from_hints.expiries, from_log.expiries);
+        assert_eq!(from_hints.get(b"key:3").unwrap(), None);
+        assert_eq!(from_hints.get(b"batched").unwrap(), Some(b"value".to_vec()));
+        assert!(from_hints.expiries.contains_key(b"session".as_slice()));
+        assert_eq!(hint_files(&path), closed);
+        assert_eq!(from_log.active, active);
+        drop((from_hints, from_log));
+
+        // Damage to the last value in segment 1 goes unnoticed until it is
+        // read, as the hints are used in place of the segment.
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(segment::path(&path, 1))
+            .unwrap();
+        file.seek(SeekFrom::End(-1)).unwrap();
+        file.write_all(&[0xff]).unwrap();
+        drop(file);
+
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        let (last_key, _) = store
+            .index
+            .iter()
+            .filter(|(_, position)| position.segment == 1)
+            .max_by_key(|(_, position)| position.offset)
+            .unwrap();
+        assert!(matches!(
+            store.get(last_key),
+            Err(ActionKVError::ChecksumMismatch { .. })
+        ));
+        assert_eq!(store.get(b"last").unwrap(), Some(b"write".to_vec()));
+        drop(store);
+
+        fs::remove_file(hint::path(&path, 1)).unwrap();
+        let mut store = options.open(&path).unwrap();
+        assert!(matches!(
+            store.load(),
+            Err(ActionKVError::ChecksumMismatch { .. })
+        ));
+    }
+
+    #[derive(Debug)]
+    struct Append;
+
+    impl MergeOperator for Append {
+        fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+            [existing.unwrap_or_default(), operand].concat()
+        }
+    }
+
+    /// Writes a store that compaction shrinks to fewer segments, with a
+    /// deleted key, overwritten ones and a merged one.
+    fn store_to_compact(path: &Path) -> (ActionKVOptions, ActionKV) {
+        let mut options = ActionKV::options();
+        options.segment_size(64).merge_operator(Append);
+        let mut store = options.open(path).unwrap();
+        for i in 0..40u32 {
+            let key = format!("key:{}", i % 8);
+            store.insert(key.as_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        store.delete(b"key:0").unwrap();
+        store.merge(b"key:1", b"+").unwrap();
+        (options, store)
+    }
+
+    fn assert_compacted_values(store: &ActionKV) {
+        assert_eq!(store.get(b"key:0").unwrap(), None);
+        let mut merged = 33u32.to_le_bytes().to_vec();
+        merged.push(b'+');
+        assert_eq!(store.get(b"key:1").unwrap(), Some(merged));
+        for i in 2..8u32 {
+            let key = format!("key:{}", i);
+            let value = (32 + i).to_le_bytes().to_vec();
+            assert_eq!(store.get(key.as_bytes()).unwrap(), Some(value));
+        }
+    }
+
+    #[test]
+    fn compaction_interrupted_before_its_marker_changes_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let (options, mut store) = store_to_compact(&path);
+        let ids = segment::list(&path).unwrap();
+
+        store.roll_over().unwrap();
+        let compacted = store.write_compacted().unwrap();
+        let tmp_path = compaction::tmp_path(&path, compacted.segments[0].id);
+        assert!(tmp_path.exists());
+        drop(store);
+
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        assert!(!tmp_path.exists());
+        assert_eq!(segment::list(&path).unwrap()[..ids.len()], ids[..]);
+        assert_compacted_values(&store);
+    }
+
+    #[test]
+    fn compaction_interrupted_after_its_marker_is_finished_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let (options, mut store) = store_to_compact(&path);
+        store.save_index().unwrap();
+
+        // Stop the install once the marker is in place and the first
+        // compacted segment renamed, before the old segments are removed.
+        store.roll_over().unwrap();
+        let compacted = store.write_compacted().unwrap();
+        assert!(compacted.segments.len() > 1);
+        let manifest = compacted.manifest(&store);
+        compaction::commit(&path, &manifest).unwrap();
+        let first = compacted.segments[0].id;
+        fs::rename(
+            compaction::tmp_path(&path, first),
+            segment::path(&path, first),
+        )
+        .unwrap();
+        drop(store);
+
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(segment::list(&path).unwrap(), manifest.installed);
+        assert!(!path.join("compaction").exists());
+        for id in &manifest.installed {
+            assert!(!compaction::tmp_path(&path, *id).exists());
+        }
+        assert_compacted_values(&store);
+
+        store.insert(b"key:2", b"new").unwrap();
+        drop(store);
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"key:2").unwrap(), Some(b"new".to_vec()));
+    }
+
+    #[test]
+    fn compaction_writes_hint_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut options = ActionKV::options();
+        options.segment_size(64);
+
+        let mut store = options.open(&path).unwrap();
+        for i in 0..50u32 {
+            let key = format!("key:{}", i % 5);
+            store.insert(key.as_bytes(), &i.to_le_bytes()).unwrap();
+        }
+        store.compact().unwrap();
+        let closed: Vec<u32> = store.segments.keys().copied().collect();
+        for id in &closed {
+            assert!(hint::path(&path, *id).exists());
+        }
+        assert!(!hint::path(&path, store.active).exists());
+        let index = store.index.clone();
+        drop(store);
+
+        fs::remove_file(path.join("index")).unwrap();
+        let mut store = options.open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index, index);
+        assert_eq!(
+            store.get(b"key:4").unwrap(),
+            Some(49u32.to_le_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn compare_and_swap_only_writes_on_a_match() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+
+        assert_eq!(store.insert_if_absent(b"leader", b"a").unwrap(), None);
+        assert_eq!(
+            store.insert_if_absent(b"leader", b"b").unwrap(),
+            Some(b"a".to_vec())
+        );
+        let mismatch = store
+            .compare_and_swap(b"leader", Some(b"b"), Some(b"c"))
+            .unwrap();
+        assert_eq!(
+            mismatch,
+            Err(CompareAndSwapError {
+                current: Some(b"a".to_vec())
+            })
+        );
+        store
+            .compare_and_swap(b"leader", Some(b"a"), Some(b"c"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(store.get(b"leader").unwrap(), Some(b"c".to_vec()));
+
+        let len = log_len(&path);
+        store
+            .compare_and_swap(b"missing", None, None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(log_len(&path), len);
+        store
+            .compare_and_swap(b"leader", Some(b"c"), None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(store.get(b"leader").unwrap(), None);
+
+        // A swap keeps the key's time-to-live, through a reload too.
+        store
+            .insert_with_ttl(b"lease", b"a", Duration::from_secs(3600))
+            .unwrap();
+        let expires_at = store.expiries[&b"lease"[..]];
+        store
+            .compare_and_swap(b"lease", Some(b"a"), Some(b"b"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(store.expiries.get(&b"lease"[..]), Some(&expires_at));
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"lease").unwrap(), Some(b"b".to_vec()));
+        assert_eq!(store.expiries.get(&b"lease"[..]), Some(&expires_at));
+    }
+
+    #[test]
+    fn counters_are_fixed_width_integers() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+
+        assert_eq!(store.incr(b"hits", 5).unwrap(), 5);
+        assert_eq!(store.decr(b"hits", 7).unwrap(), -2);
+        assert_eq!(store.decr(b"misses", 1).unwrap(), -1);
+        assert_eq!(
+            store.get(b"hits").unwrap(),
+            Some((-2i64).to_le_bytes().to_vec())
+        );
+
+        store.insert(b"name", b"action kv").unwrap();
+        store.insert(b"max", &i64::MAX.to_le_bytes()).unwrap();
+        let len = log_len(&path);
+        assert!(matches!(
+            store.incr(b"name", 1),
+            Err(ActionKVError::BadCounter { key, .. }) if key == b"name"
+        ));
+        assert!(matches!(
+            store.incr(b"max", 1),
+            Err(ActionKVError::BadCounter { .. })
+        ));
+        assert!(matches!(
+            store.incr(b"hits", i64::MIN),
+            Err(ActionKVError::BadCounter { .. })
+        ));
+        assert_eq!(log_len(&path), len);
+
+        store
+            .insert_with_ttl(b"session", &1i64.to_le_bytes(), Duration::from_secs(3600))
+            .unwrap();
+        assert_eq!(store.incr(b"session", 1).unwrap(), 2);
+        assert!(store.expiries.contains_key(&b"session"[..]));
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.incr(b"hits", 2).unwrap(), 0);
+    }
+
+    #[test]
+    fn delete_survives_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"empty", b"").unwrap();
+        store.insert(b"gone", b"soon").unwrap();
+        store.delete(b"gone").unwrap();
+        assert_eq!(store.get(b"gone").unwrap(), None);
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"empty").unwrap(), Some(vec![]));
+        assert_eq!(store.get(b"gone").unwrap(), None);
+
+        store.compact().unwrap();
+        store.index.clear();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 1);
+    }
+
+    #[test]
+    fn load_replays_log_after_index_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"1").unwrap();
+        store.insert(b"b", b"2").unwrap();
+        store.save_index().unwrap();
+        store.insert(b"a", b"3").unwrap();
+        store.delete(b"b").unwrap();
+        store.insert(b"c", b"4").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"4".to_vec()));
+
+        // A corrupt index file is ignored in favour of a full scan.
+        let index_path = path.join("index");
+        let mut bytes = fs::read(&index_path).unwrap();
+        bytes[6] ^= 0xff;
+        fs::write(&index_path, bytes).unwrap();
+
+        drop(store);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+        assert_eq!(store.get(b"a").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn index_file_is_written_periodically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let index_path = path.join("index");
+
+        let mut store = ActionKV::options()
+            .index_checkpoint_interval(64)
+            .open(&path)
+            .unwrap();
+        store.insert(b"key", b"value").unwrap();
+        assert!(!index_path.exists());
+
+        store.insert(b"key", &[0; 64]).unwrap();
+        assert!(index_path.exists());
+    }
+
+    #[test]
+    fn recovery_truncates_a_torn_final_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"kept", b"value").unwrap();
+        store.insert(b"torn", b"value").unwrap();
+        drop(store);
+
+        let valid_len = file_header::LEN + record_len(b"kept", b"value");
+        let file = OpenOptions::new()
+            .write(true)
+            .open(segment::path(&path, 1))
+            .unwrap();
+        file.set_len(valid_len + 7).unwrap();
+        drop(file);
+
+        let mut store = ActionKV::open_read_only(&path).unwrap();
+        assert!(matches!(
+            store.load(),
+            Err(ActionKVError::TruncatedRecord { offset }) if offset == valid_len
+        ));
+
+        let (mut store, discarded) = ActionKV::open_and_recover(&path).unwrap();
+        assert_eq!(discarded, 7);
+        assert_eq!(store.get(b"torn").unwrap(), None);
+        store.insert(b"after", b"crash").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"kept").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.get(b"after").unwrap(), Some(b"crash".to_vec()));
+    }
+
+    #[test]
+    fn an_index_file_ahead_of_the_log_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"first").unwrap();
+        store.insert(b"b", b"second").unwrap();
+        let lost_from = store.index[&b"b"[..]].offset;
+        store.save_index().unwrap();
+        drop(store);
+
+        // As if the index file reached the disk but the end of the log did
+        // not.
+        let file = OpenOptions::new()
+            .write(true)
+            .open(segment::path(&path, 1))
+            .unwrap();
+        file.set_len(lost_from).unwrap();
+        drop(file);
+
+        let (mut store, _) = ActionKV::open_and_recover(&path).unwrap();
+        assert_eq!(store.get(b"b").unwrap(), None);
+        store.insert(b"c", b"written over the lost record").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"first".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(
+            store.get(b"c").unwrap(),
+            Some(b"written over the lost record".to_vec())
+        );
+    }
+
+    #[test]
+    fn every_durability_policy_persists_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let policies = [
+            Durability::Always,
+            Durability::Interval(std::time::Duration::from_millis(1)),
+            Durability::Bytes(16),
+            Durability::Never,
+        ];
+
+        for (i, durability) in policies.into_iter().enumerate() {
+            let path = dir.path().join(format!("store-{}.akv", i));
+            let mut store = ActionKV::options()
+                .durability(durability)
+                .open(&path)
+                .unwrap();
+            for _ in 0..10 {
+                store.insert(b"key", b"value").unwrap();
+            }
+            store.sync().unwrap();
+            drop(store);
+
+            let mut store = ActionKV::open(&path).unwrap();
+            store.load().unwrap();
+            assert_eq!(store.get(b"key").unwrap(), Some(b"value".to_vec()));
+        }
+    }
+
+    #[test]
+    fn range_and_prefix_scans_are_ordered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open(&path).unwrap();
+        for key in [
+            "user:42:name",
+            "user:7:name",
+            "user:42:email",
+            "user:420:name",
+            "z",
+        ] {
+            store.insert(key.as_bytes(), b"v").unwrap();
+        }
+        store.delete(b"user:42:email").unwrap();
+
+        let keys = |scan: Scan| -> Vec<String> {
+            scan.map(|kv| String::from_utf8(kv.unwrap().key).unwrap())
+                .collect()
+        };
+
+        assert_eq!(
+            keys(store.scan_prefix(b"user:42:")),
+            vec!["user:42:name".to_string()]
+        );
+        assert_eq!(
+            keys(store.range(&b"user:42"[..]..&b"user:7"[..])),
+            vec!["user:420:name", "user:42:name"]
+        );
+        assert_eq!(keys(store.range(&b"user:7:name"[..]..)).len(), 2);
+        assert_eq!(keys(store.range(&b"z"[..]..&b"a"[..])).len(), 0);
+        assert_eq!(keys(store.scan_prefix(b"")).len(), 4);
+    }
+
+    #[test]
+    fn write_batch_is_applied_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"a", b"100").unwrap();
+        store.insert(b"pending", b"yes").unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"90").put(b"b", b"10").delete(b"pending");
+        store.write_batch(&batch).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"90".to_vec()));
+        assert_eq!(store.get(b"pending").unwrap(), None);
+
+        let mut batch = WriteBatch::new();
+        batch.put(b"a", b"0").put(b"b", b"100");
+        store.write_batch(&batch).unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open_read_only(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"0".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"100".to_vec()));
+        assert_eq!(store.get(b"pending").unwrap(), None);
+
+        // Tear the last batch: neither of its writes may survive.
+        let log = segment::path(&path, 1);
+        let len = fs::metadata(&log).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&log).unwrap();
+        file.set_len(len - 1).unwrap();
+        drop(file);
+
+        let (store, _) = ActionKV::open_and_recover(&path).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"90".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"10".to_vec()));
+    }
+
+    #[test]
+    fn corruption_is_reported_as_an_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"first", b"value").unwrap();
+        store.insert(b"second", b"value").unwrap();
+        drop(store);
+
+        let log = segment::path(&path, 1);
+        let mut bytes = fs::read(&log).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&log, &bytes).unwrap();
+
+        let mut store = ActionKV::open_read_only(&path).unwrap();
+        let second = file_header::LEN + record_len(b"first", b"value");
+        match store.load() {
+            Err(ActionKVError::ChecksumMismatch { offset, .. }) => assert_eq!(offset, second),
+            other => panic!("expected a checksum mismatch, got {:?}", other),
+        }
+
+        let (store, discarded) = ActionKV::open_and_recover(&path).unwrap();
+        assert_eq!(discarded, record_len(b"second", b"value"));
+        assert_eq!(fs::metadata(&log).unwrap().len(), second);
+        assert_eq!(store.get(b"first").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.get(b"second").unwrap(), None);
+
+        // Overwrite the kind byte of the first record.
+        let first = file_header::LEN;
+        bytes[first as usize + 4] = 0x7f;
+        fs::write(&log, &bytes).unwrap();
+        let mut store = ActionKV::open_read_only(&path).unwrap();
+        assert!(matches!(
+            store.load(),
+            Err(ActionKVError::BadHeader { offset, .. }) if offset == first
+        ));
+    }
+
+    #[test]
+    fn recovery_only_truncates_a_torn_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::open(&path).unwrap();
+        for i in 0..10u32 {
+            store.insert(&i.to_be_bytes(), b"value").unwrap();
+        }
+        drop(store);
+
+        // Flip a bit in the first record's value.
+        let log = segment::path(&path, 1);
+        let mut bytes = fs::read(&log).unwrap();
+        let len = bytes.len() as u64;
+        let first_end = file_header::LEN + record_len(&0u32.to_be_bytes(), b"value");
+        bytes[first_end as usize - 1] ^= 1;
+        fs::write(&log, &bytes).unwrap();
+
+        match ActionKV::open_and_recover(&path) {
+            Err(ActionKVError::DamagedSegment {
+                segment: 1,
+                offset,
+                after,
+                ..
+            }) => {
+                assert_eq!(offset, file_header::LEN);
+                assert_eq!(after, len - file_header::LEN);
+            }
+            other => panic!("expected a damaged segment, got {:?}", other),
+        }
+        assert_eq!(log_len(&path), len);
+
+        // A tail of zeros is a torn write, however long it is.
+        bytes[first_end as usize - 1] ^= 1;
+        bytes.extend_from_slice(&[0; 100]);
+        fs::write(&log, &bytes).unwrap();
+        let (store, discarded) = ActionKV::open_and_recover(&path).unwrap();
+        assert_eq!(discarded, 100);
+        assert_eq!(store.index.len(), 10);
+        drop(store);
+
+        bytes.truncate(len as usize);
+        bytes[first_end as usize - 1] ^= 1;
+        fs::write(&log, &bytes).unwrap();
+        let (store, discarded) = ActionKV::open_and_repair(&path).unwrap();
+        assert_eq!(discarded, len - file_header::LEN);
+        assert_eq!(store.index.len(), 0);
+    }
+
+    #[test]
+    fn one_writer_at_a_time_holds_the_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let (mut store, _) = ActionKV::open_and_recover(&path).unwrap();
+        store.insert(b"key", b"value").unwrap();
+        assert!(matches!(
+            ActionKV::open_and_recover(&path),
+            Err(ActionKVError::Locked)
+        ));
+        assert!(matches!(
+            ActionKV::open_and_repair(&path),
+            Err(ActionKVError::Locked)
+        ));
+        assert!(matches!(ActionKV::open(&path), Err(ActionKVError::Locked)));
+
+        // Readers are let in, but leave what looks like a compaction in
+        // progress alone, and can not write.
+        let tmp_path = compaction::tmp_path(&path, store.active + 1);
+        fs::write(&tmp_path, b"").unwrap();
+        let mut reader = ActionKV::open_read_only(&path).unwrap();
+        reader.load().unwrap();
+        assert_eq!(reader.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert!(tmp_path.exists());
+        assert!(matches!(
+            reader.insert(b"key", b"other"),
+            Err(ActionKVError::ReadOnly)
+        ));
+        assert!(matches!(reader.incr(b"n", 1), Err(ActionKVError::ReadOnly)));
+        assert!(matches!(reader.compact(), Err(ActionKVError::ReadOnly)));
+        assert!(matches!(reader.save_index(), Err(ActionKVError::ReadOnly)));
+        let reader = ActionKVHandle::new(reader);
+        assert!(matches!(
+            reader.delete(b"key"),
+            Err(ActionKVError::ReadOnly)
+        ));
+        drop(reader);
+
+        drop(store);
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"key").unwrap(), Some(b"value".to_vec()));
+        assert!(!tmp_path.exists());
+    }
+
+    #[test]
+    fn damage_in_a_closed_segment_is_never_truncated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut store = ActionKV::options().segment_size(64).open(&path).unwrap();
+        for i in 0..10u32 {
+            store.insert(&i.to_be_bytes(), b"value").unwrap();
+        }
+        assert!(store.segments.len() > 1);
+        drop(store);
+
+        let log = segment::path(&path, 1);
+        let mut bytes = fs::read(&log).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        fs::write(&log, &bytes).unwrap();
+        hint::remove(&path, 1).unwrap();
+
+        assert!(matches!(
+            ActionKV::open_and_recover(&path),
+            Err(ActionKVError::DamagedSegment { segment: 1, .. })
+        ));
+        assert_eq!(fs::metadata(&log).unwrap().len(), bytes.len() as u64);
+    }
+
+    #[test]
+    fn headerless_logs_are_refused_until_upgraded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+
+        let mut log = ByteString::new();
+        let encoding = Encoding {
+            lengths: LengthFormat::Fixed32,
+            ..Encoding::default()
+        };
+        for (kind, key, value) in [
+            (RecordKind::Value, &b"greet"[..], &b"hello"[..]),
+            (RecordKind::Value, b"gone", b"soon"),
+            (RecordKind::Tombstone, b"gone", b""),
+        ] {
+            ActionKV::write_record(&mut log, kind, key, value, None, &encoding).unwrap();
+        }
+        fs::write(&path, &log).unwrap();
+
+        assert!(matches!(
+            ActionKV::open(&path),
+            Err(ActionKVError::UnsupportedFormat { .. })
+        ));
+
+        assert!(ActionKV::upgrade(&path).unwrap());
+        assert!(!ActionKV::upgrade(&path).unwrap());
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 1);
+        assert_eq!(store.get(b"greet").unwrap(), Some(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn lengths_are_varints_and_limits_are_enforced() {
+        for len in [0, 1, 127, 128, 300, u32::MAX as u64 + 1, u64::MAX] {
+            let mut raw = ByteString::new();
+            ActionKV::write_len(&mut raw, LengthFormat::Varint, len);
+            let mut read_back = ByteString::new();
+            let decoded =
+                ActionKV::read_len(&mut &raw[..], LengthFormat::Varint, &mut read_back, 0);
+            assert_eq!(decoded.unwrap(), len);
+            assert_eq!(read_back, raw);
+        }
+        let too_long = [0xff; MAX_VARINT_LEN as usize + 1];
+        assert!(matches!(
+            ActionKV::read_len(&mut &too_long[..], LengthFormat::Varint, &mut vec![], 0),
+            Err(ActionKVError::BadHeader { .. })
+        ));
+
+        let fixed32 = Encoding {
+            lengths: LengthFormat::Fixed32,
+            ..Encoding::default()
+        };
+        let huge = u32::MAX as u64 + 1;
+        assert!(ActionKV::check_lens(0, huge, &Encoding::default(), None).is_ok());
+        assert!(matches!(
+            ActionKV::check_lens(0, huge, &fixed32, None),
+            Err(ActionKVError::OversizeRecord { max, .. }) if max == u32::MAX as u64
+        ));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::options()
+            .max_key_len(8)
+            .max_value_len(16)
+            .open(&path)
+            .unwrap();
+        store.insert(b"key", &[0; 16]).unwrap();
+        assert!(matches!(
+            store.insert(b"long key!", b""),
+            Err(ActionKVError::OversizeRecord {
+                offset: None,
+                len: 9,
+                max: 8
+            })
+        ));
+        assert!(store.insert(b"key", &[0; 17]).is_err());
+        drop(store);
+
+        let mut store = ActionKV::options().max_value_len(15).open(&path).unwrap();
+        assert!(matches!(
+            store.load(),
+            Err(ActionKVError::OversizeRecord {
+                offset: Some(_),
+                len: 16,
+                max: 15
+            })
+        ));
+    }
+
+    #[test]
+    fn expired_keys_are_absent_and_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let hour = Duration::from_secs(3600);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store
+            .insert_with_ttl(b"session:1", b"old", Duration::ZERO)
+            .unwrap();
+        store.insert_with_ttl(b"session:2", b"live", hour).unwrap();
+        store
+            .insert_with_ttl(b"session:3", b"kept", Duration::ZERO)
+            .unwrap();
+        store.insert(b"session:3", b"kept").unwrap();
+        assert_eq!(store.get(b"session:1").unwrap(), None);
+        assert!(!store.contains_key(b"session:1"));
+        assert_eq!(store.get(b"session:2").unwrap(), Some(b"live".to_vec()));
+
+        let keys: Vec<_> = store
+            .scan_prefix(b"session:")
+            .map(|kv| kv.unwrap().key)
+            .collect();
+        assert_eq!(keys, [b"session:2".to_vec(), b"session:3".to_vec()]);
+
+        store.save_index().unwrap();
+        store
+            .insert_with_ttl(b"session:4", b"old", Duration::ZERO)
+            .unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+        let before = log_len(&path);
+
+        store.compact().unwrap();
+        assert!(log_len(&path) < before);
+        store.index.clear();
+        store.load().unwrap();
+        assert_eq!(store.expiries.len(), 1);
+        assert_eq!(store.get(b"session:2").unwrap(), Some(b"live".to_vec()));
+    }
+
+    #[test]
+    fn compressed_values_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let json = br#"{"id":1,"tags":["a","b"],"body":"lorem ipsum dolor sit amet"}"#.repeat(20);
+
+        let mut store = ActionKV::options()
+            .compression(Compression::Lz4)
+            .open(&path)
+            .unwrap();
+        store.insert(b"doc", &json).unwrap();
+        store.insert(b"tiny", b"{}").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"batched", &json);
+        store.write_batch(&batch).unwrap();
+        assert!(log_len(&path) < json.len() as u64);
+        assert_eq!(store.get(b"doc").unwrap(), Some(json.clone()));
+        drop(store);
+
+        let mut header = [0u8; (file_header::LEN + PREFIX_LEN) as usize];
+        File::open(segment::path(&path, 1))
+            .unwrap()
+            .read_exact(&mut header)
+            .unwrap();
+        let header = &header[file_header::LEN as usize..];
+        assert_eq!(header[4] >> CODEC_SHIFT, Compression::Lz4.id());
+
+        // Reopened without compression, old records still read and
+        // compaction stores them uncompressed.
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"batched").unwrap(), Some(json.clone()));
+        assert_eq!(store.get(b"tiny").unwrap(), Some(b"{}".to_vec()));
+        store.compact().unwrap();
+        assert!(log_len(&path) > 2 * json.len() as u64);
+        assert_eq!(store.get(b"doc").unwrap(), Some(json));
+    }
+
+    #[test]
+    fn encrypted_store_hides_plaintext_and_rejects_wrong_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let email = b"alice@example.com".repeat(8);
+
+        let mut store = ActionKV::options()
+            .encryption_key([7; 32])
+            .compression(Compression::Lz4)
+            .open(&path)
+            .unwrap();
+        store.insert(b"user:alice", &email).unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(b"user:bob", b"bob@example.com");
+        store.write_batch(&batch).unwrap();
+        store.save_index().unwrap();
+        drop(store);
+
+        for file in fs::read_dir(&path).unwrap() {
+            let bytes = fs::read(file.unwrap().path()).unwrap();
+            for secret in [&b"alice"[..], b"bob@example"] {
+                assert!(!bytes.windows(secret.len()).any(|w| w == secret));
+            }
+        }
+
+        let mut store = ActionKV::options()
+            .encryption_key([7; 32])
+            .open(&path)
+            .unwrap();
+        store.load().unwrap();
+        assert_eq!(store.get(b"user:alice").unwrap(), Some(email));
+        assert_eq!(
+            store.get(b"user:bob").unwrap(),
+            Some(b"bob@example.com".to_vec())
+        );
+        drop(store);
+
+        assert!(matches!(
+            ActionKV::options().encryption_key([8; 32]).open(&path),
+            Err(ActionKVError::WrongKey { offset: 0 })
+        ));
+        assert!(matches!(
+            ActionKV::open(&path),
+            Err(ActionKVError::KeyRequired { offset: 0 })
+        ));
+    }
 }
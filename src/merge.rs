@@ -0,0 +1,213 @@
+//! Merge operators, which fold small operand records into a key's value so
+//! that read-modify-write updates do not have to rewrite the whole value.
+
+use std::{collections::HashMap, fmt};
+
+use crate::{scan::RecordSource, ActionKVError, ByteStr, ByteString, Position, Result};
+
+/// Combines a key's value with an operand written by
+/// [`ActionKV::merge`](crate::ActionKV::merge). Registered with
+/// [`ActionKVOptions::merge_operator`](crate::ActionKVOptions::merge_operator),
+/// and needed to read any key that has been merged into.
+///
+/// Operands are folded into the value in the order they were written,
+/// whenever the key is read and when it is compacted, so `merge` should be
+/// deterministic.
+///
+/// ```
+/// use libactionkv::MergeOperator;
+///
+/// /// Appends operands to a list of lines.
+/// #[derive(Debug)]
+/// struct AppendLine;
+///
+/// impl MergeOperator for AppendLine {
+///     fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+///         let mut value = existing.unwrap_or_default().to_vec();
+///         value.extend_from_slice(operand);
+///         value.push(b'\n');
+///         value
+///     }
+/// }
+/// ```
+pub trait MergeOperator: fmt::Debug + Send + Sync {
+    /// Returns the value of `key` once `operand` is applied to `existing`,
+    /// its value before, which is `None` if the key was absent.
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8>;
+}
+
+/// The records holding the value of a key that has been merged into: its
+/// last whole value, if there is one, and the operands written since, in
+/// log order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MergeChain {
+    pub(crate) base: Option<Position>,
+    pub(crate) operands: Vec<Position>,
+}
+
+/// The merge chain of every key whose latest record is a merge operand.
+pub(crate) type Merges = HashMap<ByteString, MergeChain>;
+
+/// Reads the records of `chain`, which must not be empty, through `source`
+/// and folds its operands into its base value with `operator`.
+pub(crate) fn fold<S: RecordSource + ?Sized>(
+    source: &S,
+    operator: Option<&dyn MergeOperator>,
+    key: &ByteStr,
+    chain: &MergeChain,
+) -> Result<ByteString> {
+    let mut value = match chain.base {
+        Some(position) => Some(source.read_record(position)?.value),
+        None => None,
+    };
+    if !chain.operands.is_empty() {
+        let operator = operator.ok_or(ActionKVError::NoMergeOperator)?;
+        for position in &chain.operands {
+            let operand = source.read_record(*position)?.value;
+            value = Some(operator.merge(key, value.as_deref(), &operand));
+        }
+    }
+
+    Ok(value.expect("merge chains are never empty"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, thread, time::Duration};
+
+    use super::*;
+    use crate::{ActionKV, ActionKVHandle, ActionKVOptions};
+
+    /// Adds little-endian `u64` operands to a counter.
+    #[derive(Debug)]
+    struct Add;
+
+    impl MergeOperator for Add {
+        fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+            let read = |bytes: &[u8]| u64::from_le_bytes(bytes.try_into().unwrap());
+            let sum = existing.map_or(0, read) + read(operand);
+            sum.to_le_bytes().to_vec()
+        }
+    }
+
+    fn options() -> ActionKVOptions {
+        let mut options = ActionKV::options();
+        options.merge_operator(Add).segment_size(256);
+        options
+    }
+
+    fn open(path: &Path) -> ActionKV {
+        let mut store = options().open(path).unwrap();
+        store.load().unwrap();
+        store
+    }
+
+    fn count(store: &ActionKV, key: &ByteStr) -> Option<u64> {
+        let value = store.get(key).unwrap()?;
+        Some(u64::from_le_bytes(value.try_into().unwrap()))
+    }
+
+    #[test]
+    fn merges_into_expired_keys_start_afresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = open(&path);
+
+        let ttl = Duration::from_millis(1);
+        store
+            .insert_with_ttl(b"hits", &10u64.to_le_bytes(), ttl)
+            .unwrap();
+        thread::sleep(Duration::from_millis(5));
+        store.merge(b"hits", &2u64.to_le_bytes()).unwrap();
+        assert_eq!(count(&store, b"hits"), Some(2));
+        assert!(!store.expiries.contains_key(&b"hits"[..]));
+        drop(store);
+
+        let store = open(&path);
+        assert_eq!(count(&store, b"hits"), Some(2));
+
+        let handle = ActionKVHandle::new(store);
+        handle
+            .insert_with_ttl(b"misses", &10u64.to_le_bytes(), ttl)
+            .unwrap();
+        thread::sleep(Duration::from_millis(5));
+        handle.merge(b"misses", &3u64.to_le_bytes()).unwrap();
+        assert_eq!(
+            handle.get(b"misses").unwrap(),
+            Some(3u64.to_le_bytes().to_vec())
+        );
+        drop(handle);
+
+        let store = open(&path);
+        assert_eq!(count(&store, b"misses"), Some(3));
+    }
+
+    #[test]
+    fn operands_fold_into_values_on_read_reload_and_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = open(&path);
+
+        store.insert(b"hits", &10u64.to_le_bytes()).unwrap();
+        for _ in 0..30 {
+            store.merge(b"hits", &1u64.to_le_bytes()).unwrap();
+            store.merge(b"misses", &2u64.to_le_bytes()).unwrap();
+        }
+        store.merge(b"reset", &5u64.to_le_bytes()).unwrap();
+        store.delete(b"reset").unwrap();
+        store.merge(b"reset", &1u64.to_le_bytes()).unwrap();
+        assert!(store.segments.len() > 1);
+
+        assert_eq!(count(&store, b"hits"), Some(40));
+        assert_eq!(count(&store, b"misses"), Some(60));
+        assert_eq!(count(&store, b"reset"), Some(1));
+        let scanned: Vec<ByteString> = store.scan_prefix(b"").map(|kv| kv.unwrap().key).collect();
+        assert_eq!(scanned, [&b"hits"[..], b"misses", b"reset"]);
+        store.save_index().unwrap();
+        store.merge(b"hits", &2u64.to_le_bytes()).unwrap();
+        drop(store);
+
+        // From the index file, and then from the segments alone.
+        let mut store = open(&path);
+        assert_eq!(count(&store, b"hits"), Some(42));
+        drop(store);
+        fs::remove_file(path.join("index")).unwrap();
+        store = open(&path);
+        assert_eq!(count(&store, b"hits"), Some(42));
+        assert_eq!(count(&store, b"misses"), Some(60));
+
+        store.compact().unwrap();
+        assert!(store.merges.is_empty());
+        store.merge(b"misses", &3u64.to_le_bytes()).unwrap();
+        assert_eq!(count(&store, b"misses"), Some(63));
+        drop(store);
+
+        let store = open(&path);
+        assert_eq!(count(&store, b"hits"), Some(42));
+        assert_eq!(count(&store, b"misses"), Some(63));
+        assert_eq!(count(&store, b"reset"), Some(1));
+    }
+
+    #[test]
+    fn merges_need_an_operator() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        assert!(matches!(
+            store.merge(b"hits", &1u64.to_le_bytes()),
+            Err(ActionKVError::NoMergeOperator)
+        ));
+        drop(store);
+
+        let mut store = open(&path);
+        store.merge(b"hits", &1u64.to_le_bytes()).unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert!(matches!(
+            store.get(b"hits"),
+            Err(ActionKVError::NoMergeOperator)
+        ));
+    }
+}
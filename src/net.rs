@@ -0,0 +1,312 @@
+//! A simple length-prefixed binary protocol for serving an [`ActionKV`] over
+//! TCP, along with a client for it.
+//!
+//! Every request is `op: u8 | key_len: u32 | key | val_len: u32 | value` and
+//! every response is `status: u8 | len: u32 | payload`, with lengths in
+//! little endian like the log itself. Requests without a value send a zero
+//! `val_len`. The payload of a successful `get` is the value; the payload of
+//! an error is a UTF-8 message. `incr` and `decr` send their delta as the
+//! value, and get the counter's new value back as the payload, both as 8-byte
+//! little-endian integers.
+//!
+//! [`ActionKV`]: crate::ActionKV
+
+use std::{
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    thread,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt as _, WriteBytesExt as _};
+
+use crate::{ActionKVError, ActionKVHandle, ByteStr, ByteString, Result};
+
+/// Longest key or value accepted in a single frame, to stop a bad length
+/// prefix from exhausting memory.
+const MAX_FIELD_LEN: u32 = 256 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Op {
+    Get = 1,
+    Insert = 2,
+    Update = 3,
+    Delete = 4,
+    Incr = 5,
+    Decr = 6,
+}
+
+impl Op {
+    fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            1 => Some(Op::Get),
+            2 => Some(Op::Insert),
+            3 => Some(Op::Update),
+            4 => Some(Op::Delete),
+            5 => Some(Op::Incr),
+            6 => Some(Op::Decr),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Status {
+    Ok = 0,
+    NotFound = 1,
+    Error = 2,
+}
+
+impl Status {
+    fn from_u8(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Status::Ok),
+            1 => Some(Status::NotFound),
+            2 => Some(Status::Error),
+            _ => None,
+        }
+    }
+}
+
+/// Accepts connections on `listener` forever, serving each from its own
+/// thread with a clone of `store`.
+pub fn serve(listener: TcpListener, store: ActionKVHandle) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let store = store.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle_connection(stream, &store) {
+                eprintln!("connection closed: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, store: &ActionKVHandle) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+
+    loop {
+        let op = match reader.read_u8() {
+            Ok(op) => op,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let key = read_field(&mut reader)?;
+        let value = read_field(&mut reader)?;
+
+        let result = match Op::from_u8(op) {
+            Some(Op::Get) => store.get(&key),
+            Some(Op::Insert) => store.insert(&key, &value).map(|_| Some(vec![])),
+            Some(Op::Update) => store.update(&key, &value).map(|_| Some(vec![])),
+            Some(Op::Delete) => store.delete(&key).map(|_| Some(vec![])),
+            Some(op @ (Op::Incr | Op::Decr)) => {
+                let Ok(delta) = <[u8; 8]>::try_from(value.as_slice()) else {
+                    let message = "the delta must be an 8-byte integer";
+                    write_frame(&mut writer, Status::Error, message.as_bytes())?;
+                    writer.flush()?;
+                    continue;
+                };
+                let delta = i64::from_le_bytes(delta);
+                let count = match op {
+                    Op::Incr => store.incr(&key, delta),
+                    _ => store.decr(&key, delta),
+                };
+                count.map(|count| Some(count.to_le_bytes().to_vec()))
+            }
+            None => {
+                let message = format!("unknown operation {:#04x}", op);
+                write_frame(&mut writer, Status::Error, message.as_bytes())?;
+                writer.flush()?;
+                continue;
+            }
+        };
+
+        match result {
+            Ok(Some(value)) => write_frame(&mut writer, Status::Ok, &value)?,
+            Ok(None) => write_frame(&mut writer, Status::NotFound, b"")?,
+            Err(err) => write_frame(&mut writer, Status::Error, err.to_string().as_bytes())?,
+        }
+        writer.flush()?;
+    }
+}
+
+fn read_field<R: Read>(reader: &mut R) -> io::Result<ByteString> {
+    let len = reader.read_u32::<LittleEndian>()?;
+    if len > MAX_FIELD_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("field of {} bytes is longer than {}", len, MAX_FIELD_LEN),
+        ));
+    }
+
+    let mut field = vec![0; len as usize];
+    reader.read_exact(&mut field)?;
+    Ok(field)
+}
+
+fn write_field<W: Write>(writer: &mut W, field: &ByteStr) -> Result<()> {
+    if field.len() > MAX_FIELD_LEN as usize {
+        return Err(ActionKVError::OversizeRecord {
+            offset: None,
+            len: field.len() as u64,
+            max: MAX_FIELD_LEN as u64,
+        });
+    }
+
+    writer.write_u32::<LittleEndian>(field.len() as u32)?;
+    writer.write_all(field)?;
+    Ok(())
+}
+
+/// Writes a response. A payload longer than clients accept is replaced by an
+/// error, as sending it would leave the client out of step with the stream.
+fn write_frame<W: Write>(writer: &mut W, status: Status, payload: &ByteStr) -> io::Result<()> {
+    if payload.len() > MAX_FIELD_LEN as usize {
+        let message = format!(
+            "response of {} bytes is longer than {}",
+            payload.len(),
+            MAX_FIELD_LEN
+        );
+        return write_frame(writer, Status::Error, message.as_bytes());
+    }
+
+    writer.write_u8(status as u8)?;
+    writer.write_u32::<LittleEndian>(payload.len() as u32)?;
+    writer.write_all(payload)
+}
+
+/// Client for a store served by [`serve`], such as the `akv_server` binary.
+///
+/// ```no_run
+/// # use libactionkv::net::Client;
+/// let mut client = Client::connect("127.0.0.1:4000")?;
+/// client.insert(b"greeting", b"hello")?;
+/// assert_eq!(client.get(b"greeting")?, Some(b"hello".to_vec()));
+/// # Ok::<(), libactionkv::ActionKVError>(())
+/// ```
+#[derive(Debug)]
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    pub fn get(&mut self, key: &ByteStr) -> Result<Option<ByteString>> {
+        self.request(Op::Get, key, b"")
+    }
+
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.request(Op::Insert, key, value).map(|_| ())
+    }
+
+    pub fn update(&mut self, key: &ByteStr, value: &ByteStr) -> Result<()> {
+        self.request(Op::Update, key, value).map(|_| ())
+    }
+
+    pub fn delete(&mut self, key: &ByteStr) -> Result<()> {
+        self.request(Op::Delete, key, b"").map(|_| ())
+    }
+
+    /// See [`ActionKV::incr`](crate::ActionKV::incr).
+    pub fn incr(&mut self, key: &ByteStr, delta: i64) -> Result<i64> {
+        self.counter_request(Op::Incr, key, delta)
+    }
+
+    /// See [`ActionKV::decr`](crate::ActionKV::decr).
+    pub fn decr(&mut self, key: &ByteStr, delta: i64) -> Result<i64> {
+        self.counter_request(Op::Decr, key, delta)
+    }
+
+    fn counter_request(&mut self, op: Op, key: &ByteStr, delta: i64) -> Result<i64> {
+        let payload = self.request(op, key, &delta.to_le_bytes())?;
+        match payload.as_deref().map(<[u8; 8]>::try_from) {
+            Some(Ok(count)) => Ok(i64::from_le_bytes(count)),
+            _ => Err(
+                io::Error::new(io::ErrorKind::InvalidData, "malformed counter in response").into(),
+            ),
+        }
+    }
+
+    fn request(&mut self, op: Op, key: &ByteStr, value: &ByteStr) -> Result<Option<ByteString>> {
+        self.writer.write_u8(op as u8)?;
+        write_field(&mut self.writer, key)?;
+        write_field(&mut self.writer, value)?;
+        self.writer.flush()?;
+
+        let status = self.reader.read_u8()?;
+        let payload = read_field(&mut self.reader)?;
+        match Status::from_u8(status) {
+            Some(Status::Ok) => Ok(Some(payload)),
+            Some(Status::NotFound) => Ok(None),
+            Some(Status::Error) => Err(ActionKVError::Remote(
+                String::from_utf8_lossy(&payload).into_owned(),
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown response status {:#04x}", status),
+            )
+            .into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ActionKV;
+
+    #[test]
+    fn oversize_responses_become_errors() {
+        let mut frame = Vec::new();
+        let payload = vec![0; MAX_FIELD_LEN as usize + 1];
+        write_frame(&mut frame, Status::Ok, &payload).unwrap();
+
+        let mut reader = &frame[..];
+        assert_eq!(reader.read_u8().unwrap(), Status::Error as u8);
+        let message = read_field(&mut reader).unwrap();
+        assert!(String::from_utf8(message).unwrap().contains("longer than"));
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn client_round_trips_through_server() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, ActionKVHandle::new(store)));
+
+        let mut client = Client::connect(addr).unwrap();
+        assert_eq!(client.get(b"key").unwrap(), None);
+        client.insert(b"key", b"value").unwrap();
+        client.update(b"key", b"\x00binary\xff").unwrap();
+
+        let mut other = Client::connect(addr).unwrap();
+        assert_eq!(other.get(b"key").unwrap(), Some(b"\x00binary\xff".to_vec()));
+        other.insert(b"empty", b"").unwrap();
+        assert_eq!(client.get(b"empty").unwrap(), Some(vec![]));
+
+        client.delete(b"key").unwrap();
+        assert_eq!(other.get(b"key").unwrap(), None);
+
+        assert_eq!(client.incr(b"hits", 5).unwrap(), 5);
+        assert_eq!(other.decr(b"hits", 2).unwrap(), 3);
+        assert!(matches!(
+            client.incr(b"empty", 1),
+            Err(ActionKVError::Remote(message)) if message.contains("counter")
+        ));
+    }
+}
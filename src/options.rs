@@ -0,0 +1,177 @@
+use std::{fmt, path::Path, sync::Arc};
+
+use crate::{
+    crypto::KEY_LEN, upgrade, ActionKV, Compression, Durability, MergeOperator, Recovery, Result,
+    DEFAULT_INDEX_CHECKPOINT_INTERVAL, DEFAULT_SEGMENT_SIZE,
+};
+
+/// Options for opening an [`ActionKV`], in the style of
+/// [`std::fs::OpenOptions`]. `Debug` output leaves out the encryption key.
+///
+/// ```no_run
+/// use std::{path::Path, time::Duration};
+/// use libactionkv::{ActionKV, Durability};
+///
+/// let store = ActionKV::options()
+///     .durability(Durability::Interval(Duration::from_millis(100)))
+///     .open(Path::new("store.akv"))?;
+/// # Ok::<(), libactionkv::ActionKVError>(())
+/// ```
+#[derive(Clone)]
+pub struct ActionKVOptions {
+    pub(crate) durability: Durability,
+    pub(crate) index_checkpoint_interval: u64,
+    pub(crate) segment_size: u64,
+    pub(crate) compression: Compression,
+    pub(crate) encryption_key: Option<[u8; KEY_LEN]>,
+    pub(crate) max_key_len: u64,
+    pub(crate) max_value_len: u64,
+    pub(crate) merge_operator: Option<Arc<dyn MergeOperator>>,
+}
+
+impl ActionKVOptions {
+    pub fn new() -> Self {
+        ActionKVOptions {
+            durability: Durability::default(),
+            index_checkpoint_interval: DEFAULT_INDEX_CHECKPOINT_INTERVAL,
+            segment_size: DEFAULT_SEGMENT_SIZE,
+            compression: Compression::default(),
+            encryption_key: None,
+            max_key_len: u64::MAX,
+            max_value_len: u64::MAX,
+            merge_operator: None,
+        }
+    }
+
+    /// Sets when writes are synced to disk. Defaults to
+    /// [`Durability::Never`].
+    pub fn durability(&mut self, durability: Durability) -> &mut Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets how many bytes may be appended to the log before the index file
+    /// is rewritten. `load` replays at most this much of the log on startup.
+    pub fn index_checkpoint_interval(&mut self, bytes: u64) -> &mut Self {
+        self.index_checkpoint_interval = bytes;
+        self
+    }
+
+    /// Sets the size, in bytes, after which the active segment of the log is
+    /// closed and a new one started. Defaults to 64 MiB. A segment can
+    /// exceed it by the last record written to it.
+    pub fn segment_size(&mut self, bytes: u64) -> &mut Self {
+        self.segment_size = bytes;
+        self
+    }
+
+    /// Sets how values written from now on are compressed. Defaults to
+    /// [`Compression::None`]. Records already in the log are read whatever
+    /// they were written with, and are recompressed by compaction.
+    pub fn compression(&mut self, compression: Compression) -> &mut Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypts the key and value of every record written from now on with
+    /// ChaCha20-Poly1305 under `key`, and decrypts existing records with it.
+    /// The index file is encrypted too.
+    ///
+    /// Opening fails with [`ActionKVError::WrongKey`] if the log is encrypted
+    /// with a different key. Records written before a key was set are still
+    /// read, and are encrypted by the next compaction.
+    ///
+    /// [`ActionKVError::WrongKey`]: crate::ActionKVError::WrongKey
+    pub fn encryption_key(&mut self, key: [u8; KEY_LEN]) -> &mut Self {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// Sets the longest key, in bytes, that may be written or read. Longer
+    /// keys are refused with [`ActionKVError::OversizeRecord`], and a record
+    /// in the log with one is reported as damaged. Defaults to no limit
+    /// beyond that of the log's format, which is 4 GiB for logs written
+    /// before varint lengths were added.
+    ///
+    /// [`ActionKVError::OversizeRecord`]: crate::ActionKVError::OversizeRecord
+    pub fn max_key_len(&mut self, bytes: u64) -> &mut Self {
+        self.max_key_len = bytes;
+        self
+    }
+
+    /// Sets the longest value, in bytes, that may be written or read, in the
+    /// same way as [`max_key_len`](Self::max_key_len).
+    pub fn max_value_len(&mut self, bytes: u64) -> &mut Self {
+        self.max_value_len = bytes;
+        self
+    }
+
+    /// Sets the operator that folds the operands written by
+    /// [`ActionKV::merge`] into values. A store that has been merged into
+    /// must always be opened with the same operator.
+    pub fn merge_operator<M: MergeOperator + 'static>(&mut self, operator: M) -> &mut Self {
+        self.merge_operator = Some(Arc::new(operator));
+        self
+    }
+
+    /// Opens the store in the directory at `path`, creating it if needed,
+    /// and locks it against other writers until it is dropped; if another
+    /// process has it open, this fails with [`ActionKVError::Locked`]. Call
+    /// [`ActionKV::load`] to read the existing records.
+    ///
+    /// [`ActionKVError::Locked`]: crate::ActionKVError::Locked
+    pub fn open(&self, path: &Path) -> Result<ActionKV> {
+        ActionKV::with_options(path, self, false)
+    }
+
+    /// Opens the existing store at `path` for reading only. See
+    /// [`ActionKV::open_read_only`].
+    pub fn open_read_only(&self, path: &Path) -> Result<ActionKV> {
+        ActionKV::with_options(path, self, true)
+    }
+
+    /// Opens and loads the store at `path`, truncating a torn final record.
+    /// See [`ActionKV::open_and_recover`].
+    pub fn open_and_recover(&self, path: &Path) -> Result<(ActionKV, u64)> {
+        let mut store = ActionKV::with_options(path, self, false)?;
+        let discarded = store.replay(Recovery::TornTail)?;
+
+        Ok((store, discarded))
+    }
+
+    /// Opens and loads the store at `path`, truncating every segment at its
+    /// first damaged record. See [`ActionKV::open_and_repair`].
+    pub fn open_and_repair(&self, path: &Path) -> Result<(ActionKV, u64)> {
+        let mut store = ActionKV::with_options(path, self, false)?;
+        let discarded = store.replay(Recovery::Truncate)?;
+
+        Ok((store, discarded))
+    }
+
+    /// Converts the store at `path` to the current format. See
+    /// [`ActionKV::upgrade`].
+    pub fn upgrade(&self, path: &Path) -> Result<bool> {
+        upgrade::upgrade(path, self)
+    }
+}
+
+impl fmt::Debug for ActionKVOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ActionKVOptions")
+            .field("durability", &self.durability)
+            .field("index_checkpoint_interval", &self.index_checkpoint_interval)
+            .field("segment_size", &self.segment_size)
+            .field("compression", &self.compression)
+            .field("encrypted", &self.encryption_key.is_some())
+            .field("max_key_len", &self.max_key_len)
+            .field("max_value_len", &self.max_value_len)
+            .field("merge_operator", &self.merge_operator)
+            .finish()
+    }
+}
+
+impl Default for ActionKVOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
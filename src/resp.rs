@@ -0,0 +1,559 @@
+//! Redis protocol (RESP2) front end for an [`ActionKV`], so that `redis-cli`
+//! and existing Redis client libraries can talk to a store directly.
+//!
+//! Supported commands are `GET`, `SET`, `DEL`, `EXISTS`, `MGET`, `MSET`,
+//! `INCR`, `DECR`, `INCRBY`, `DECRBY`, `SCAN`, `KEYS` and `PING`, plus the
+//! `COMMAND`, `SELECT 0` and `QUIT` housekeeping that clients send when they
+//! connect. `SCAN` cursors are only valid on the connection that issued them.
+//!
+//! Unlike in Redis, counters are stored as 8-byte little-endian integers, as
+//! [`ActionKV::incr`](crate::ActionKV::incr) and every other front end store
+//! them, rather than as decimal strings, so that the same counter can be
+//! changed through any of them. `INCR` and the like reply with the count as
+//! an integer, but `GET` returns the stored bytes.
+//!
+//! [`ActionKV`]: crate::ActionKV
+
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, BufWriter, Read as _, Write},
+    net::{TcpListener, TcpStream},
+    ops::Bound,
+    thread,
+};
+
+use crate::{ActionKVHandle, ByteStr, ByteString, WriteBatch};
+
+/// Longest bulk string accepted from a client.
+const MAX_BULK_LEN: usize = 256 * 1024 * 1024;
+
+/// Longest inline command or protocol line accepted from a client.
+const MAX_LINE_LEN: u64 = 64 * 1024;
+
+/// Most arguments accepted in a single command.
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// Most unfinished `SCAN` cursors remembered per connection.
+const MAX_CURSORS: usize = 1024;
+
+/// Default number of keys examined by each `SCAN` call, as in Redis.
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Accepts connections on `listener` forever, serving each from its own
+/// thread with a clone of `store`.
+pub fn serve(listener: TcpListener, store: ActionKVHandle) -> io::Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let store = store.clone();
+        thread::spawn(move || {
+            if let Err(err) = handle_connection(stream, store) {
+                eprintln!("connection closed: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle_connection(stream: TcpStream, store: ActionKVHandle) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut connection = Connection {
+        store,
+        cursors: BTreeMap::new(),
+        next_cursor: 1,
+    };
+
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                Reply::Error(format!("ERR Protocol error: {}", err)).write(&mut writer)?;
+                return writer.flush();
+            }
+            Err(err) => return Err(err),
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let quit = args[0].eq_ignore_ascii_case(b"QUIT");
+        connection.execute(args).write(&mut writer)?;
+
+        // Replies to pipelined commands are sent together.
+        if quit || reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+/// Reads one command, either as an array of bulk strings or as an inline
+/// command. Returns `None` at the end of the stream.
+fn read_command<R: BufRead>(reader: &mut R) -> io::Result<Option<Vec<ByteString>>> {
+    let first = match reader.fill_buf()?.first() {
+        Some(byte) => *byte,
+        None => return Ok(None),
+    };
+
+    if first != b'*' {
+        let line = read_line(reader)?;
+        let args = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let count = parse_length(&read_line(reader)?[1..])?;
+    if count > MAX_ARGS {
+        return Err(protocol_error("invalid multibulk length"));
+    }
+
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(reader)?;
+        if line.first() != Some(&b'$') {
+            return Err(protocol_error("expected '$'"));
+        }
+        let len = parse_length(&line[1..])?;
+        if len > MAX_BULK_LEN {
+            return Err(protocol_error("invalid bulk length"));
+        }
+
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(protocol_error("bulk string not terminated by CRLF"));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+
+    Ok(Some(args))
+}
+
+/// Reads a line, without its line ending.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<ByteString> {
+    let mut line = ByteString::new();
+    reader.take(MAX_LINE_LEN).read_until(b'\n', &mut line)?;
+
+    if line.pop() != Some(b'\n') {
+        return Err(match line.len() as u64 {
+            len if len + 1 >= MAX_LINE_LEN => protocol_error("line too long"),
+            _ => io::ErrorKind::UnexpectedEof.into(),
+        });
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(line)
+}
+
+/// Parses a non-negative length. A negative length, meaning a null array,
+/// is treated as zero.
+fn parse_length(digits: &ByteStr) -> io::Result<usize> {
+    match parse_integer(digits) {
+        Some(len) => Ok(len.max(0) as usize),
+        None => Err(protocol_error("invalid length")),
+    }
+}
+
+fn parse_integer(digits: &ByteStr) -> Option<i64> {
+    std::str::from_utf8(digits).ok()?.parse().ok()
+}
+
+fn keys(args: &[ByteString]) -> Vec<&ByteStr> {
+    args.iter().map(Vec::as_slice).collect()
+}
+
+fn protocol_error(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+enum Reply {
+    Simple(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<ByteString>),
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    fn ok() -> Self {
+        Reply::Simple("OK")
+    }
+
+    fn wrong_arity(command: &str) -> Self {
+        Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command
+        ))
+    }
+
+    fn not_an_integer() -> Self {
+        Reply::Error("ERR value is not an integer or out of range".into())
+    }
+
+    fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        match self {
+            Reply::Simple(message) => write!(out, "+{}\r\n", message),
+            Reply::Error(message) => {
+                // Line breaks would end the error early.
+                let message = message.replace(['\r', '\n'], " ");
+                write!(out, "-{}\r\n", message)
+            }
+            Reply::Integer(n) => write!(out, ":{}\r\n", n),
+            Reply::Bulk(None) => out.write_all(b"$-1\r\n"),
+            Reply::Bulk(Some(bytes)) => {
+                write!(out, "${}\r\n", bytes.len())?;
+                out.write_all(bytes)?;
+                out.write_all(b"\r\n")
+            }
+            Reply::Array(replies) => {
+                write!(out, "*{}\r\n", replies.len())?;
+                replies.iter().try_for_each(|reply| reply.write(out))
+            }
+        }
+    }
+}
+
+impl From<crate::Result<Reply>> for Reply {
+    fn from(result: crate::Result<Reply>) -> Self {
+        result.unwrap_or_else(|err| Reply::Error(format!("ERR {}", err)))
+    }
+}
+
+struct Connection {
+    store: ActionKVHandle,
+    /// Last key returned for each unfinished `SCAN` cursor.
+    cursors: BTreeMap<u64, ByteString>,
+    next_cursor: u64,
+}
+
+impl Connection {
+    fn execute(&mut self, args: Vec<ByteString>) -> Reply {
+        let name = args[0].to_ascii_uppercase();
+        let args = &args[1..];
+
+        match (name.as_slice(), args.len()) {
+            (b"PING", 0) => Reply::Simple("PONG"),
+            (b"PING", 1) => Reply::Bulk(Some(args[0].clone())),
+            (b"PING", _) => Reply::wrong_arity("ping"),
+            (b"GET", 1) => self.store.get(&args[0]).map(Reply::Bulk).into(),
+            (b"GET", _) => Reply::wrong_arity("get"),
+            (b"SET", 2) => self
+                .store
+                .insert(&args[0], &args[1])
+                .map(|_| Reply::ok())
+                .into(),
+            (b"SET", n) if n > 2 => Reply::Error("ERR syntax error".into()),
+            (b"SET", _) => Reply::wrong_arity("set"),
+            (b"DEL", n) if n > 0 => self
+                .store
+                .delete_existing(&keys(args))
+                .map(|count| Reply::Integer(count as i64))
+                .into(),
+            (b"DEL", _) => Reply::wrong_arity("del"),
+            (b"EXISTS", n) if n > 0 => {
+                Reply::Integer(self.store.count_existing(&keys(args)) as i64)
+            }
+            (b"EXISTS", _) => Reply::wrong_arity("exists"),
+            (b"MGET", n) if n > 0 => args
+                .iter()
+                .map(|key| self.store.get(key).map(Reply::Bulk))
+                .collect::<crate::Result<_>>()
+                .map(Reply::Array)
+                .into(),
+            (b"MGET", _) => Reply::wrong_arity("mget"),
+            (b"MSET", n) if n > 0 && n % 2 == 0 => {
+                let mut batch = WriteBatch::new();
+                for pair in args.chunks(2) {
+                    batch.put(&pair[0], &pair[1]);
+                }
+                self.store.write_batch(&batch).map(|_| Reply::ok()).into()
+            }
+            (b"MSET", _) => Reply::wrong_arity("mset"),
+            (b"INCR", 1) => self.store.incr(&args[0], 1).map(Reply::Integer).into(),
+            (b"INCR", _) => Reply::wrong_arity("incr"),
+            (b"DECR", 1) => self.store.decr(&args[0], 1).map(Reply::Integer).into(),
+            (b"DECR", _) => Reply::wrong_arity("decr"),
+            (b"INCRBY", 2) => match parse_integer(&args[1]) {
+                Some(delta) => self.store.incr(&args[0], delta).map(Reply::Integer).into(),
+                None => Reply::not_an_integer(),
+            },
+            (b"INCRBY", _) => Reply::wrong_arity("incrby"),
+            (b"DECRBY", 2) => match parse_integer(&args[1]) {
+                Some(delta) => self.store.decr(&args[0], delta).map(Reply::Integer).into(),
+                None => Reply::not_an_integer(),
+            },
+            (b"DECRBY", _) => Reply::wrong_arity("decrby"),
+            (b"SCAN", n) if n > 0 => self.scan(args),
+            (b"SCAN", _) => Reply::wrong_arity("scan"),
+            (b"KEYS", 1) => {
+                let keys = self.store.keys_from(Bound::Unbounded, usize::MAX);
+                let matching = keys.into_iter().filter(|key| glob_match(&args[0], key));
+                Reply::Array(matching.map(|key| Reply::Bulk(Some(key))).collect())
+            }
+            (b"KEYS", _) => Reply::wrong_arity("keys"),
+            (b"COMMAND", _) => Reply::Array(vec![]),
+            (b"SELECT", 1) if args[0] == b"0" => Reply::ok(),
+            (b"SELECT", 1) => Reply::Error("ERR DB index is out of range".into()),
+            (b"SELECT", _) => Reply::wrong_arity("select"),
+            (b"QUIT", _) => Reply::ok(),
+            _ => Reply::Error(format!(
+                "ERR unknown command '{}'",
+                String::from_utf8_lossy(&name)
+            )),
+        }
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`. Keys are visited in order
+    /// and each cursor remembers the last key it returned, so a key present
+    /// for the whole iteration is always returned exactly once.
+    fn scan(&mut self, args: &[ByteString]) -> Reply {
+        let start = match parse_integer(&args[0]) {
+            Some(0) => Bound::Unbounded,
+            Some(cursor) => match self.cursors.remove(&(cursor as u64)) {
+                Some(last_key) => Bound::Excluded(last_key),
+                None => return Reply::Error("ERR invalid cursor".into()),
+            },
+            None => return Reply::Error("ERR invalid cursor".into()),
+        };
+
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        for option in args[1..].chunks(2) {
+            match option {
+                [name, value] if name.eq_ignore_ascii_case(b"MATCH") => pattern = Some(value),
+                [name, value] if name.eq_ignore_ascii_case(b"COUNT") => {
+                    match parse_integer(value) {
+                        Some(n) if n > 0 => count = n as usize,
+                        _ => return Reply::Error("ERR syntax error".into()),
+                    }
+                }
+                _ => return Reply::Error("ERR syntax error".into()),
+            }
+        }
+
+        let keys = self
+            .store
+            .keys_from(start.as_ref().map(Vec::as_slice), count);
+
+        let mut next = 0;
+        if keys.len() == count {
+            if self.cursors.len() >= MAX_CURSORS {
+                self.cursors.pop_first();
+            }
+            next = self.next_cursor;
+            self.next_cursor += 1;
+            self.cursors.insert(next, keys[keys.len() - 1].clone());
+        }
+
+        let keys = keys
+            .into_iter()
+            .filter(|key| pattern.is_none_or(|pattern| glob_match(pattern, key)))
+            .map(|key| Reply::Bulk(Some(key)))
+            .collect();
+
+        Reply::Array(vec![
+            Reply::Bulk(Some(next.to_string().into_bytes())),
+            Reply::Array(keys),
+        ])
+    }
+}
+
+/// Redis-style glob matching: `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\`
+/// to escape.
+fn glob_match(mut pattern: &ByteStr, mut string: &ByteStr) -> bool {
+    while let Some(&p) = pattern.first() {
+        match p {
+            b'*' => {
+                while pattern.first() == Some(&b'*') {
+                    pattern = &pattern[1..];
+                }
+                if pattern.is_empty() {
+                    return true;
+                }
+                return (0..=string.len()).any(|i| glob_match(pattern, &string[i..]));
+            }
+            b'?' => {
+                if string.is_empty() {
+                    return false;
+                }
+            }
+            b'[' => {
+                let Some(&c) = string.first() else {
+                    return false;
+                };
+                pattern = &pattern[1..];
+                let negate = pattern.first() == Some(&b'^');
+                if negate {
+                    pattern = &pattern[1..];
+                }
+
+                let mut matched = false;
+                loop {
+                    match pattern {
+                        [] => break,
+                        [b']', ..] => break,
+                        [b'\\', escaped, ..] => {
+                            matched |= *escaped == c;
+                            pattern = &pattern[2..];
+                        }
+                        [low, b'-', high, ..] if *high != b']' => {
+                            let (low, high) = (*low.min(high), *low.max(high));
+                            matched |= (low..=high).contains(&c);
+                            pattern = &pattern[3..];
+                        }
+                        [literal, ..] => {
+                            matched |= *literal == c;
+                            pattern = &pattern[1..];
+                        }
+                    }
+                }
+                if matched == negate {
+                    return false;
+                }
+            }
+            b'\\' if pattern.len() > 1 => {
+                pattern = &pattern[1..];
+                if string.first() != Some(&pattern[0]) {
+                    return false;
+                }
+            }
+            literal => {
+                if string.first() != Some(&literal) {
+                    return false;
+                }
+            }
+        }
+
+        pattern = pattern.get(1..).unwrap_or_default();
+        string = &string[1..];
+    }
+
+    string.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read as _;
+
+    use super::*;
+    use crate::ActionKV;
+
+    #[test]
+    fn glob_patterns_match_like_redis() {
+        assert!(glob_match(b"user:*", b"user:42"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"h?llo", b"hallo"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"h[ae]llo", b"hello"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(glob_match(b"h\\*llo", b"h*llo"));
+        assert!(!glob_match(b"h\\*llo", b"hello"));
+        assert!(glob_match(b"*:*:name", b"user:42:name"));
+        assert!(!glob_match(b"user", b"user:42"));
+    }
+
+    fn request(stream: &mut TcpStream, command: &[&str]) -> String {
+        let mut encoded = format!("*{}\r\n", command.len());
+        for arg in command {
+            encoded.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        stream.write_all(encoded.as_bytes()).unwrap();
+
+        // Every reply in these tests fits in one read.
+        let mut reply = [0; 4096];
+        let len = stream.read(&mut reply).unwrap();
+        String::from_utf8_lossy(&reply[..len]).into_owned()
+    }
+
+    #[test]
+    fn counters_are_shared_with_the_other_front_ends() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        let handle = ActionKVHandle::new(store);
+        let resp_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let resp_addr = resp_listener.local_addr().unwrap();
+        let net_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let net_addr = net_listener.local_addr().unwrap();
+        let resp_handle = handle.clone();
+        thread::spawn(move || serve(resp_listener, resp_handle));
+        let net_handle = handle.clone();
+        thread::spawn(move || crate::net::serve(net_listener, net_handle));
+
+        let mut stream = TcpStream::connect(resp_addr).unwrap();
+        let mut client = crate::net::Client::connect(net_addr).unwrap();
+        assert_eq!(request(&mut stream, &["INCRBY", "n", "5"]), ":5\r\n");
+        assert_eq!(client.incr(b"n", 2).unwrap(), 7);
+        assert_eq!(request(&mut stream, &["DECR", "n"]), ":6\r\n");
+        assert_eq!(client.decr(b"n", 10).unwrap(), -4);
+        assert_eq!(handle.incr(b"n", 0).unwrap(), -4);
+        assert_eq!(
+            handle.get(b"n").unwrap(),
+            Some((-4i64).to_le_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn serves_redis_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve(listener, ActionKVHandle::new(store)));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        assert_eq!(request(&mut stream, &["PING"]), "+PONG\r\n");
+        assert_eq!(request(&mut stream, &["set", "a", "1"]), "+OK\r\n");
+        assert_eq!(request(&mut stream, &["GET", "a"]), "$1\r\n1\r\n");
+        assert_eq!(request(&mut stream, &["GET", "missing"]), "$-1\r\n");
+        assert_eq!(
+            request(&mut stream, &["MSET", "b", "2", "c", "3"]),
+            "+OK\r\n"
+        );
+        assert_eq!(
+            request(&mut stream, &["MGET", "a", "x", "c"]),
+            "*3\r\n$1\r\n1\r\n$-1\r\n$1\r\n3\r\n"
+        );
+        assert_eq!(request(&mut stream, &["EXISTS", "a", "a", "x"]), ":2\r\n");
+        assert_eq!(request(&mut stream, &["DEL", "a", "x", "a"]), ":1\r\n");
+        assert_eq!(
+            request(&mut stream, &["KEYS", "*"]),
+            "*2\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(
+            request(&mut stream, &["SCAN", "0", "COUNT", "1"]),
+            "*2\r\n$1\r\n1\r\n*1\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(
+            request(&mut stream, &["SCAN", "1", "COUNT", "5"]),
+            "*2\r\n$1\r\n0\r\n*1\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(request(&mut stream, &["INCR", "n"]), ":1\r\n");
+        assert_eq!(request(&mut stream, &["INCRBY", "n", "41"]), ":42\r\n");
+        assert_eq!(request(&mut stream, &["DECRBY", "n", "50"]), ":-8\r\n");
+        assert_eq!(request(&mut stream, &["DECR", "n"]), ":-9\r\n");
+        assert_eq!(
+            request(&mut stream, &["INCRBY", "n", "one"]),
+            "-ERR value is not an integer or out of range\r\n"
+        );
+        assert!(request(&mut stream, &["INCR", "b"]).starts_with("-ERR can not change"));
+        assert_eq!(request(&mut stream, &["DEL", "n"]), ":1\r\n");
+        assert_eq!(
+            request(&mut stream, &["GET"]),
+            "-ERR wrong number of arguments for 'get' command\r\n"
+        );
+
+        stream.write_all(b"PING\r\n").unwrap();
+        let mut reply = [0; 7];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+    }
+}
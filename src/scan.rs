@@ -0,0 +1,158 @@
+use std::{
+    collections::{btree_map, BTreeMap},
+    ops::Bound,
+};
+
+use crate::{
+    merge::{self, Merges},
+    ActionKV, ByteStr, ByteString, Expiries, KeyValuePair, MergeOperator, Position, Result,
+};
+
+/// Iterator over the live key/value pairs in a range of keys, in key order.
+///
+/// Returned by [`ActionKV::range`] and [`ActionKV::scan_prefix`], and their
+/// counterparts on a [`Snapshot`](crate::Snapshot). Values are
+/// read from the log lazily as the iterator advances. Keys that had expired
+/// when the scan started are skipped.
+pub struct Scan<'a> {
+    source: &'a (dyn RecordSource + Sync),
+    keys: btree_map::Range<'a, ByteString, Position>,
+    expiries: &'a Expiries,
+    now: u64,
+}
+
+/// Where a scan reads its values from: the store's own descriptors, or
+/// those of a handle.
+pub(crate) trait RecordSource {
+    fn read_record(&self, position: Position) -> Result<KeyValuePair>;
+
+    /// The merge chains of the store read from, and its merge operator.
+    fn merges(&self) -> (&Merges, Option<&dyn MergeOperator>);
+
+    /// Reads the value of `key`, whose latest record is at `position`,
+    /// folding in any merge operands.
+    fn read_value(&self, key: &ByteStr, position: Position) -> Result<KeyValuePair> {
+        let (merges, operator) = self.merges();
+        match merges.get(key) {
+            Some(chain) => Ok(KeyValuePair {
+                key: key.to_vec(),
+                value: merge::fold(self, operator, key, chain)?,
+            }),
+            None => self.read_record(position),
+        }
+    }
+}
+
+impl RecordSource for ActionKV {
+    fn read_record(&self, position: Position) -> Result<KeyValuePair> {
+        self.get_at(position)
+    }
+
+    fn merges(&self) -> (&Merges, Option<&dyn MergeOperator>) {
+        (&self.merges, self.merge_operator.as_deref())
+    }
+}
+
+impl<'a> Scan<'a> {
+    pub(crate) fn new(
+        source: &'a (dyn RecordSource + Sync),
+        keys: btree_map::Range<'a, ByteString, Position>,
+        expiries: &'a Expiries,
+        now: u64,
+    ) -> Self {
+        Scan {
+            source,
+            keys,
+            expiries,
+            now,
+        }
+    }
+
+    /// Reads the values through `source` instead of the store itself.
+    pub(crate) fn with_source(self, source: &'a (dyn RecordSource + Sync)) -> Self {
+        Scan { source, ..self }
+    }
+}
+
+impl Iterator for Scan<'_> {
+    type Item = Result<KeyValuePair>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (key, position) = self
+            .keys
+            .find(|(key, _)| self.expiries.get(*key).is_none_or(|at| *at > self.now))?;
+        Some(self.source.read_value(key, *position))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (lower, upper) = self.keys.size_hint();
+        if self.expiries.is_empty() {
+            (lower, upper)
+        } else {
+            (0, upper)
+        }
+    }
+}
+
+/// Returns the bounds covering every key that starts with `prefix`.
+pub(crate) fn prefix_bounds(prefix: &ByteStr) -> (Bound<&ByteStr>, Bound<ByteString>) {
+    let mut upper = prefix.to_vec();
+    while let Some(last) = upper.pop() {
+        if last < u8::MAX {
+            upper.push(last + 1);
+            return (Bound::Included(prefix), Bound::Excluded(upper));
+        }
+    }
+
+    (Bound::Included(prefix), Bound::Unbounded)
+}
+
+/// The entries of `index` whose keys fall between `start` and `end`.
+pub(crate) fn index_range<'a>(
+    index: &'a BTreeMap<ByteString, Position>,
+    start: Bound<&ByteStr>,
+    end: Bound<&ByteStr>,
+) -> btree_map::Range<'a, ByteString, Position> {
+    if is_empty_range(start, end) {
+        let empty: &ByteStr = b"";
+        index.range::<ByteStr, _>((Bound::Included(empty), Bound::Excluded(empty)))
+    } else {
+        index.range::<ByteStr, _>((start, end))
+    }
+}
+
+/// Whether `range` selects no keys at all. `BTreeMap::range` panics on such
+/// ranges rather than returning nothing.
+fn is_empty_range(start: Bound<&ByteStr>, end: Bound<&ByteStr>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+        (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix_bounds_carry_past_max_bytes() {
+        assert_eq!(
+            prefix_bounds(b"ab\xff"),
+            (
+                Bound::Included(&b"ab\xff"[..]),
+                Bound::Excluded(b"ac".to_vec())
+            )
+        );
+        assert_eq!(
+            prefix_bounds(b"\xff\xff"),
+            (Bound::Included(&b"\xff\xff"[..]), Bound::Unbounded)
+        );
+        assert_eq!(
+            prefix_bounds(b""),
+            (Bound::Included(&b""[..]), Bound::Unbounded)
+        );
+    }
+}
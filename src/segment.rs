@@ -0,0 +1,104 @@
+//! The log is a directory of segment files, numbered in the order they were
+//! started. Records are appended to the newest, the active segment, which is
+//! closed once it reaches the configured size and a new one started. Closed
+//! segments are never appended to again, so they can be compacted or
+//! archived without disturbing writes.
+//!
+//! Every segment starts with its own file header.
+
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+};
+
+use crate::{file_header, ActionKV, ActionKVError, Encoding, LengthFormat, Result};
+
+const EXTENSION: &str = ".log";
+
+/// Digits in a segment's file name, which are zero-padded so that listings
+/// sort in order.
+const ID_DIGITS: usize = 8;
+
+pub(crate) fn path(dir: &Path, id: u32) -> PathBuf {
+    dir.join(format!("{:0width$}{}", id, EXTENSION, width = ID_DIGITS))
+}
+
+fn parse(name: &str) -> Option<u32> {
+    let digits = name.strip_suffix(EXTENSION)?;
+    if digits.len() < ID_DIGITS || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// The ids of the segments in `dir`, in ascending order.
+pub(crate) fn list(dir: &Path) -> Result<Vec<u32>> {
+    let mut ids = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        if let Some(id) = entry?.file_name().to_str().and_then(parse) {
+            ids.push(id);
+        }
+    }
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Opens the closed segment `id` for reading, checking its header.
+pub(crate) fn open_closed(dir: &Path, id: u32, encoding: &Encoding) -> Result<File> {
+    let file = File::open(path(dir, id))?;
+    match file_header::read(&file, encoding)? {
+        Some(header) => check_lengths(header.lengths(), id)?,
+        None => {
+            return Err(ActionKVError::BadFileHeader {
+                reason: format!("segment {} is empty", id),
+            })
+        }
+    }
+    Ok(file)
+}
+
+/// Opens the segment `id` for appending, creating it and writing its header
+/// if it does not exist yet.
+pub(crate) fn open_active(dir: &Path, id: u32, encoding: &Encoding) -> Result<File> {
+    let path = path(dir, id);
+    let created = !path.exists();
+    let file = ActionKV::open_log(&path)?;
+    check_lengths(file_header::prepare(&file, &path, encoding)?.lengths(), id)?;
+    if created {
+        ActionKV::sync_parent_dir(&path)?;
+    }
+    Ok(file)
+}
+
+/// Segments are only ever written in the current format; older logs are
+/// converted as a whole by [`ActionKV::upgrade`].
+fn check_lengths(lengths: LengthFormat, id: u32) -> Result<()> {
+    if lengths != LengthFormat::default() {
+        return Err(ActionKVError::UnsupportedFormat {
+            reason: format!("segment {} is in format version 1", id),
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_names_sort_and_parse() {
+        let dir = Path::new("store");
+        assert_eq!(path(dir, 7), dir.join("00000007.log"));
+        assert_eq!(parse("00000007.log"), Some(7));
+        assert_eq!(parse("4294967295.log"), Some(u32::MAX));
+        for name in [
+            "7.log",
+            "00000007.log.compact",
+            "00000007.hint",
+            "0000000x.log",
+            "index",
+        ] {
+            assert_eq!(parse(name), None);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+//! Read-only views of a store as it was at one point in its log.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
+
+use crate::{
+    merge::Merges, now_millis, scan, scan::RecordSource, ActionKV, ActionKVError, ByteStr,
+    ByteString, Encoding, Expiries, KeyValuePair, MergeOperator, Position, Result, Scan,
+};
+
+/// A read-only view of a store, pinned to the end of its log when the
+/// snapshot was taken. Reads only see records written before that point,
+/// however the store changes afterwards. Keys with a time-to-live still
+/// expire on time.
+///
+/// Returned by [`ActionKV::snapshot`]. The snapshot has its own copy of the
+/// index and its own descriptors for the segments it reads from, so it does
+/// not borrow the store and compaction can not pull segments out from under
+/// it: a segment that compaction replaces stays readable through the
+/// snapshot until the snapshot is dropped, although its disk space is only
+/// freed then.
+#[derive(Debug)]
+pub struct Snapshot {
+    position: Position,
+    index: BTreeMap<ByteString, Position>,
+    expiries: Expiries,
+    merges: Merges,
+    merge_operator: Option<Arc<dyn MergeOperator>>,
+    segments: BTreeMap<u32, File>,
+    encoding: Encoding,
+}
+
+impl Snapshot {
+    pub(crate) fn new(store: &ActionKV) -> Result<Self> {
+        let mut segments = BTreeMap::new();
+        for (id, file) in &store.segments {
+            segments.insert(*id, file.try_clone()?);
+        }
+        segments.insert(store.active, store.file.try_clone()?);
+
+        Ok(Snapshot {
+            position: store.end()?,
+            index: store.index.clone(),
+            expiries: store.expiries.clone(),
+            merges: store.merges.clone(),
+            merge_operator: store.merge_operator.clone(),
+            segments,
+            encoding: store.encoding.clone(),
+        })
+    }
+
+    /// The end of the log when the snapshot was taken.
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    pub fn get(&self, key: &ByteStr) -> Result<Option<ByteString>> {
+        match self.live_position(key) {
+            Some(position) => Ok(Some(self.read_value(key, position)?.value)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn contains_key(&self, key: &ByteStr) -> bool {
+        self.index.contains_key(key) && !self.is_expired(key, now_millis())
+    }
+
+    /// See [`ActionKV::range`].
+    pub fn range<'a, R: RangeBounds<&'a ByteStr>>(&self, range: R) -> Scan<'_> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        self.scan_bounds(start, end)
+    }
+
+    /// See [`ActionKV::scan_prefix`].
+    pub fn scan_prefix(&self, prefix: &ByteStr) -> Scan<'_> {
+        let (start, end) = scan::prefix_bounds(prefix);
+        self.scan_bounds(start, end.as_ref().map(Vec::as_slice))
+    }
+
+    fn scan_bounds(&self, start: Bound<&ByteStr>, end: Bound<&ByteStr>) -> Scan<'_> {
+        let keys = scan::index_range(&self.index, start, end);
+        Scan::new(self, keys, &self.expiries, now_millis())
+    }
+
+    pub(crate) fn live_position(&self, key: &ByteStr) -> Option<Position> {
+        match self.index.get(key) {
+            Some(_) if self.is_expired(key, now_millis()) => None,
+            position => position.copied(),
+        }
+    }
+
+    fn is_expired(&self, key: &ByteStr, now: u64) -> bool {
+        self.expiries
+            .get(key)
+            .is_some_and(|expires_at| *expires_at <= now)
+    }
+}
+
+impl RecordSource for Snapshot {
+    fn read_record(&self, position: Position) -> Result<KeyValuePair> {
+        let file = self
+            .segments
+            .get(&position.segment)
+            .ok_or(ActionKVError::MissingSegment {
+                segment: position.segment,
+            })?;
+        ActionKV::read_record_at(file, position.offset, &self.encoding)
+    }
+
+    fn merges(&self) -> (&Merges, Option<&dyn MergeOperator>) {
+        (&self.merges, self.merge_operator.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_ignore_later_writes_and_compaction() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::options().segment_size(128).open(&path).unwrap();
+        for i in 0..20u32 {
+            let key = format!("key:{:02}", i);
+            store.insert(key.as_bytes(), &i.to_le_bytes()).unwrap();
+        }
+
+        let snapshot = store.snapshot().unwrap();
+        for i in 0..20u32 {
+            let key = format!("key:{:02}", i);
+            store.insert(key.as_bytes(), b"changed").unwrap();
+        }
+        store.delete(b"key:00").unwrap();
+        store.insert(b"key:20", b"new").unwrap();
+        store.compact().unwrap();
+        store.insert(b"key:01", b"after compaction").unwrap();
+
+        assert!(snapshot.position() < store.snapshot().unwrap().position());
+        assert_eq!(
+            snapshot.get(b"key:00").unwrap(),
+            Some(0u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(
+            snapshot.get(b"key:01").unwrap(),
+            Some(1u32.to_le_bytes().to_vec())
+        );
+        assert_eq!(snapshot.get(b"key:20").unwrap(), None);
+        assert!(!snapshot.contains_key(b"key:20"));
+
+        let values: Vec<ByteString> = snapshot
+            .scan_prefix(b"key:")
+            .map(|kv| kv.unwrap().value)
+            .collect();
+        let expected: Vec<ByteString> = (0..20u32).map(|i| i.to_le_bytes().to_vec()).collect();
+        assert_eq!(values, expected);
+        assert_eq!(snapshot.range(&b"key:05"[..]..&b"key:07"[..]).count(), 2);
+
+        assert_eq!(store.get(b"key:00").unwrap(), None);
+        assert_eq!(
+            store.get(b"key:01").unwrap(),
+            Some(b"after compaction".to_vec())
+        );
+        assert_eq!(store.get(b"key:02").unwrap(), Some(b"changed".to_vec()));
+    }
+}
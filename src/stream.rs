@@ -0,0 +1,519 @@
+//! Reading and writing values a piece at a time, for values too large to
+//! comfortably hold in memory.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    mem,
+};
+
+use crate::{
+    crypto::{Cipher, Sealer, MAX_SEALED_LEN, NONCE_LEN, TAG_LEN},
+    segment, ActionKV, ActionKVError, ByteStr, ByteString, Compression, Position, ReadAt,
+    RecordKind, Result, CRC32, ENCRYPTED_FLAG,
+};
+
+/// How much of a value is gathered before it is appended to the log, and
+/// read at a time while checking a checksum.
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// The generator polynomial of CRC-32/CKSUM, without its `x^32` term.
+const CRC32_POLY: u32 = 0x04c1_1db7;
+/// CRC-32/CKSUM inverts its register at the end.
+const CRC32_XOROUT: u32 = 0xffff_ffff;
+
+/// Reader over a single value, returned by [`ActionKV::get_reader`].
+#[derive(Debug)]
+pub struct ValueReader<'a> {
+    source: Source<'a>,
+    len: u64,
+    position: u64,
+}
+
+#[derive(Debug)]
+enum Source<'a> {
+    /// The value is stored as it is, starting at `start` in the log.
+    Log { file: &'a File, start: u64 },
+    /// The value had to be decoded whole.
+    Memory(ByteString),
+}
+
+impl<'a> ValueReader<'a> {
+    /// A reader over a value that has already been read whole.
+    pub(crate) fn in_memory(value: ByteString) -> Self {
+        ValueReader {
+            len: value.len() as u64,
+            source: Source::Memory(value),
+            position: 0,
+        }
+    }
+
+    /// Opens the value of the record at `position`, checking its checksum.
+    pub(crate) fn open(store: &'a ActionKV, position: Position) -> Result<Self> {
+        let file = store.segment(position.segment)?;
+        let offset = position.offset;
+        let mut buf = BufReader::with_capacity(CHUNK_LEN, ReadAt { file, offset });
+        let header = ActionKV::read_header(&mut buf, offset, &store.encoding)?
+            .ok_or(ActionKVError::TruncatedRecord { offset })?;
+
+        if header.sealed.is_some() || header.codec != Compression::None {
+            let kv = ActionKV::read_record_at(file, offset, &store.encoding)?;
+            return Ok(Self::in_memory(kv.value));
+        }
+
+        let data_len = header.data_len(offset)?;
+        let mut digest = header.digest();
+        let mut data = buf.take(data_len);
+        let mut chunk = vec![0; CHUNK_LEN];
+        let mut checked = 0;
+        loop {
+            match data.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    digest.update(&chunk[..n]);
+                    checked += n as u64;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        if checked < data_len {
+            return Err(ActionKVError::TruncatedRecord { offset });
+        }
+        header.check_checksum(digest.finalize(), offset)?;
+
+        Ok(ValueReader {
+            source: Source::Log {
+                file,
+                start: offset + header.len() + header.key_len,
+            },
+            len: header.val_len,
+            position: 0,
+        })
+    }
+
+    /// Length of the value in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.position);
+        let want = (buf.len() as u64).min(remaining) as usize;
+        let read = match &self.source {
+            Source::Log { file, start } => ReadAt {
+                file,
+                offset: start + self.position,
+            }
+            .read(&mut buf[..want])?,
+            Source::Memory(value) => {
+                let start = self.position as usize;
+                buf[..want].copy_from_slice(&value[start..start + want]);
+                want
+            }
+        };
+
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for ValueReader<'_> {
+    /// Seeking past the end is allowed, as with files; reads there return
+    /// nothing.
+    fn seek(&mut self, to: SeekFrom) -> io::Result<u64> {
+        let position = match to {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                Ok(position)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// Writer that appends a single value to the log as it is written,
+/// returned by [`ActionKV::insert_writer`].
+///
+/// The record's header is written with a blank checksum, which
+/// [`finish`](Self::finish) fills in once the whole value has gone by. Until
+/// then, a crash leaves a damaged record at the end of the log, which
+/// [`ActionKV::open_and_recover`] removes.
+pub struct ValueWriter<'a> {
+    store: &'a mut ActionKV,
+    key: ByteString,
+    /// Where the record starts in the log.
+    position: Position,
+    /// Bytes of the value still to come.
+    remaining: u64,
+    /// Bytes of the record appended to the log so far.
+    appended: u64,
+    /// Bytes of the record waiting to be appended.
+    buffer: ByteString,
+    /// Checksum of the record so far, with zeros in place of the nonce and
+    /// tag of an encrypted record.
+    digest: crc::Digest<'static, u32>,
+    /// Encryption of the key and value, and the nonce it uses.
+    sealer: Option<(Sealer, [u8; NONCE_LEN])>,
+    /// Offset of the nonce and tag within the record.
+    sealed_at: u64,
+    finished: bool,
+}
+
+impl<'a> ValueWriter<'a> {
+    pub(crate) fn new(store: &'a mut ActionKV, key: &ByteStr, len: u64) -> Result<Self> {
+        store.check_writable()?;
+        let encoding = &store.encoding;
+        ActionKV::check_lens(key.len() as u64, len, encoding, None)?;
+        let sealed_len = (key.len() as u64).saturating_add(len);
+        if encoding.cipher.is_some() && sealed_len > MAX_SEALED_LEN {
+            return Err(ActionKVError::OversizeRecord {
+                offset: None,
+                len: sealed_len,
+                max: MAX_SEALED_LEN,
+            });
+        }
+
+        let mut aad = ByteString::new();
+        let encrypted = if encoding.cipher.is_some() {
+            ENCRYPTED_FLAG
+        } else {
+            0
+        };
+        aad.push(RecordKind::Value as u8 | encrypted);
+        ActionKV::write_len(&mut aad, encoding.lengths, key.len() as u64);
+        ActionKV::write_len(&mut aad, encoding.lengths, len);
+
+        let sealer = match &encoding.cipher {
+            Some(cipher) => {
+                let nonce = Cipher::nonce()?;
+                Some((cipher.sealer(&nonce, &aad), nonce))
+            }
+            None => None,
+        };
+
+        let mut buffer = ByteString::with_capacity(CHUNK_LEN);
+        buffer.extend_from_slice(&[0; 4]);
+        buffer.extend_from_slice(&aad);
+        let sealed_at = buffer.len() as u64;
+        if sealer.is_some() {
+            buffer.extend_from_slice(&[0; NONCE_LEN + TAG_LEN]);
+        }
+        let mut digest = CRC32.digest();
+        digest.update(&buffer[4..]);
+
+        let position = Position {
+            segment: store.active,
+            offset: (&store.file).seek(SeekFrom::End(0))?,
+        };
+        let mut writer = ValueWriter {
+            store,
+            key: key.to_vec(),
+            position,
+            remaining: len,
+            appended: 0,
+            buffer,
+            digest,
+            sealer,
+            sealed_at,
+            finished: false,
+        };
+        writer.push(key)?;
+
+        Ok(writer)
+    }
+
+    /// Encrypts and checksums `data`, and queues it to be appended.
+    fn push(&mut self, data: &[u8]) -> io::Result<()> {
+        let start = self.buffer.len();
+        self.buffer.extend_from_slice(data);
+        if let Some((sealer, _)) = &mut self.sealer {
+            sealer.update(&mut self.buffer[start..])?;
+        }
+        self.digest.update(&self.buffer[start..]);
+
+        if self.buffer.len() >= CHUNK_LEN {
+            self.append()?;
+        }
+        Ok(())
+    }
+
+    fn append(&mut self) -> io::Result<()> {
+        (&self.store.file).write_all(&self.buffer)?;
+        self.appended += self.buffer.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    /// Completes the record and adds it to the index. Fails if fewer bytes
+    /// were written than the length given to
+    /// [`insert_writer`](ActionKV::insert_writer), in which case the value
+    /// is discarded.
+    pub fn finish(mut self) -> Result<()> {
+        if self.remaining > 0 {
+            return Err(ActionKVError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} bytes of the value were never written", self.remaining),
+            )));
+        }
+        self.append()?;
+
+        let data_len = self.appended - self.sealed_at;
+        let digest = mem::replace(&mut self.digest, CRC32.digest());
+        let patch = OpenOptions::new()
+            .write(true)
+            .open(segment::path(self.store.path(), self.position.segment))?;
+        let checksum = match self.sealer.take() {
+            Some((sealer, nonce)) => {
+                let mut sealed = [0; NONCE_LEN + TAG_LEN];
+                sealed[..NONCE_LEN].copy_from_slice(&nonce);
+                sealed[NONCE_LEN..].copy_from_slice(&sealer.finish());
+                write_at(&patch, &sealed, self.position.offset + self.sealed_at)?;
+
+                // The digest covered zeros where the nonce and tag are, and
+                // CRCs are linear, so adding theirs moved past the data that
+                // follows them gives the checksum of the record.
+                let data_len = data_len - sealed.len() as u64;
+                let sealed_crc =
+                    crc32_append_zeros(CRC32.checksum(&sealed) ^ CRC32_XOROUT, data_len);
+                digest.finalize() ^ sealed_crc
+            }
+            None => digest.finalize(),
+        };
+        write_at(&patch, &checksum.to_le_bytes(), self.position.offset)?;
+
+        self.finished = true;
+        let store = &mut *self.store;
+        store.appended(self.appended)?;
+        store.commit_record(
+            RecordKind::Value,
+            &self.key,
+            None,
+            self.position,
+            self.appended,
+        )
+    }
+}
+
+impl Write for ValueWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() as u64 > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "value is longer than the length given to insert_writer",
+            ));
+        }
+
+        self.push(buf)?;
+        self.remaining -= buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.append()
+    }
+}
+
+impl Drop for ValueWriter<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.store.file.set_len(self.position.offset);
+        }
+    }
+}
+
+/// Writes all of `bytes` at `offset`, leaving the file's cursor alone.
+fn write_at(file: &File, bytes: &[u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    std::os::unix::fs::FileExt::write_all_at(file, bytes, offset)?;
+    #[cfg(windows)]
+    {
+        let mut written = 0;
+        while written < bytes.len() {
+            written += std::os::windows::fs::FileExt::seek_write(
+                file,
+                &bytes[written..],
+                offset + written as u64,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Advances the register of CRC-32/CKSUM, before its final inversion, past
+/// `len` zero bytes. That is a multiplication by `x^(8 * len)` modulo the
+/// polynomial.
+fn crc32_append_zeros(register: u32, len: u64) -> u32 {
+    let mut factor = 1;
+    let mut power = 1 << 8;
+    let mut len = len;
+    while len > 0 {
+        if len & 1 == 1 {
+            factor = gf2_multiply(factor, power);
+        }
+        power = gf2_multiply(power, power);
+        len >>= 1;
+    }
+    gf2_multiply(register, factor)
+}
+
+/// Multiplies two polynomials over GF(2) modulo the CRC polynomial.
+fn gf2_multiply(a: u32, b: u32) -> u32 {
+    let mut product = 0u32;
+    for bit in (0..32).rev() {
+        let carry = product & 0x8000_0000 != 0;
+        product <<= 1;
+        if carry {
+            product ^= CRC32_POLY;
+        }
+        if b & (1 << bit) != 0 {
+            product ^= a;
+        }
+    }
+    product
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc_of_a_later_part_can_be_added() {
+        let (head, middle, tail) = (&b"head"[..], &b"middle"[..], &[7u8; 1000][..]);
+        let zeros = vec![0; middle.len()];
+        let with_zeros = CRC32.checksum(&[head, &zeros, tail].concat());
+        let middle_crc =
+            crc32_append_zeros(CRC32.checksum(middle) ^ CRC32_XOROUT, tail.len() as u64);
+
+        assert_eq!(
+            with_zeros ^ middle_crc,
+            CRC32.checksum(&[head, middle, tail].concat())
+        );
+    }
+
+    #[test]
+    fn values_stream_in_and_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let value: ByteString = (0..200_000u32).map(|i| (i * 7 % 251) as u8).collect();
+
+        for encryption_key in [None, Some([3; 32])] {
+            let path = dir.path().join(format!("{}.akv", encryption_key.is_some()));
+            let mut options = ActionKV::options();
+            if let Some(key) = encryption_key {
+                options.encryption_key(key);
+            }
+
+            let mut store = options.open(&path).unwrap();
+            store.insert(b"before", b"small").unwrap();
+            let mut writer = store.insert_writer(b"blob", value.len() as u64).unwrap();
+            for chunk in value.chunks(9_999) {
+                writer.write_all(chunk).unwrap();
+            }
+            writer.finish().unwrap();
+            store.insert(b"after", b"small").unwrap();
+            assert_eq!(store.get(b"blob").unwrap().as_ref(), Some(&value));
+            drop(store);
+
+            let mut store = options.open(&path).unwrap();
+            store.load().unwrap();
+            let mut reader = store.get_reader(b"blob").unwrap().unwrap();
+            assert_eq!(reader.len(), value.len() as u64);
+            let mut read_back = ByteString::new();
+            reader.read_to_end(&mut read_back).unwrap();
+            assert_eq!(read_back, value);
+
+            reader.seek(SeekFrom::End(-10)).unwrap();
+            let mut end = [0; 20];
+            assert_eq!(reader.read(&mut end).unwrap(), 10);
+            assert_eq!(end[..10], value[value.len() - 10..]);
+            reader.seek(SeekFrom::Start(1000)).unwrap();
+            reader.read_exact(&mut end).unwrap();
+            assert_eq!(end, value[1000..1020]);
+            assert!(reader.seek(SeekFrom::Current(-5000)).is_err());
+            assert!(store.get_reader(b"missing").unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn encrypted_values_stop_short_of_the_cipher_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::options()
+            .encryption_key([3; 32])
+            .open(&dir.path().join("store.akv"))
+            .unwrap();
+        assert!(matches!(
+            store.insert_writer(b"huge", MAX_SEALED_LEN),
+            Err(ActionKVError::OversizeRecord {
+                max: MAX_SEALED_LEN,
+                ..
+            })
+        ));
+        store.insert_writer(b"", MAX_SEALED_LEN).unwrap();
+    }
+
+    #[test]
+    fn unfinished_values_are_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"kept", b"value").unwrap();
+        let log = segment::path(&path, 1);
+        let len = std::fs::metadata(&log).unwrap().len();
+
+        let mut writer = store.insert_writer(b"short", 100).unwrap();
+        writer.write_all(&[1; 50]).unwrap();
+        assert!(writer.write_all(&[1; 51]).is_err());
+        assert!(writer.finish().is_err());
+        drop(store.insert_writer(b"dropped", 10).unwrap());
+
+        assert_eq!(std::fs::metadata(&log).unwrap().len(), len);
+        assert_eq!(store.get(b"short").unwrap(), None);
+        store.insert(b"next", b"value").unwrap();
+        drop(store);
+
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+    }
+
+    #[test]
+    fn damaged_values_are_caught_before_streaming() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let mut store = ActionKV::open(&path).unwrap();
+        store.insert(b"key", &[5; 1000]).unwrap();
+        drop(store);
+
+        let log = segment::path(&path, 1);
+        let mut bytes = std::fs::read(&log).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+        std::fs::write(&log, bytes).unwrap();
+
+        let mut store = ActionKV::open(&path).unwrap();
+        let position = Position {
+            segment: 1,
+            offset: crate::file_header::LEN,
+        };
+        store.index.insert(b"key".to_vec(), position);
+        assert!(matches!(
+            store.get_reader(b"key"),
+            Err(ActionKVError::ChecksumMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,239 @@
+//! Optimistic read-write transactions.
+
+use std::collections::BTreeMap;
+
+use crate::{
+    scan::RecordSource, ActionKV, ActionKVError, ByteStr, ByteString, Position, Result, WriteBatch,
+};
+
+/// What a transaction reads from: the store itself, or a handle to it.
+pub(crate) trait View {
+    /// Reads `key`'s value along with the position of the record holding
+    /// it, unless it is absent or expired.
+    fn read_live(&self, key: &ByteStr) -> Result<Option<(Position, ByteString)>>;
+}
+
+impl View for ActionKV {
+    fn read_live(&self, key: &ByteStr) -> Result<Option<(Position, ByteString)>> {
+        match self.live_position(key) {
+            Some(position) => Ok(Some((position, self.read_value(key, position)?.value))),
+            None => Ok(None),
+        }
+    }
+}
+
+/// A transaction in progress, passed to the closure given to
+/// [`ActionKV::transaction`](crate::ActionKV::transaction).
+///
+/// Reads see the transaction's own writes, and otherwise the value a key
+/// had when the transaction first read it. Writes are gathered until the
+/// closure returns, and then appended to the log as a single batch, unless
+/// another writer has changed a key the transaction read in the meantime.
+pub struct Transaction<'a> {
+    view: &'a dyn View,
+    /// The store's compaction generation when the transaction started.
+    generation: u64,
+    /// Every key read from the store, with where its value was and what it
+    /// was, or `None` if the key was absent.
+    reads: BTreeMap<ByteString, Option<(Position, ByteString)>>,
+    /// The value every key written will have, or `None` if it is deleted.
+    writes: BTreeMap<ByteString, Option<ByteString>>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(view: &'a dyn View, generation: u64) -> Self {
+        Transaction {
+            view,
+            generation,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &ByteStr) -> Result<Option<ByteString>> {
+        if let Some(value) = self.writes.get(key) {
+            return Ok(value.clone());
+        }
+        if let Some(read) = self.reads.get(key) {
+            return Ok(read.as_ref().map(|(_, value)| value.clone()));
+        }
+
+        let read = self.view.read_live(key)?;
+        let value = read.as_ref().map(|(_, value)| value.clone());
+        self.reads.insert(key.to_vec(), read);
+        Ok(value)
+    }
+
+    pub fn insert(&mut self, key: &ByteStr, value: &ByteStr) {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    pub fn delete(&mut self, key: &ByteStr) {
+        self.writes.insert(key.to_vec(), None);
+    }
+
+    /// Ends the transaction, returning what is needed to commit it.
+    pub(crate) fn finish(self) -> Commit {
+        let mut batch = WriteBatch::new();
+        for (key, value) in &self.writes {
+            match value {
+                Some(value) => batch.put(key, value),
+                None => batch.delete(key),
+            };
+        }
+
+        let reads = self
+            .reads
+            .into_iter()
+            .map(|(key, read)| (key, read.map(|(position, _)| position)))
+            .collect();
+        Commit {
+            generation: self.generation,
+            reads,
+            batch,
+        }
+    }
+}
+
+/// The reads and writes of a finished transaction.
+pub(crate) struct Commit {
+    generation: u64,
+    reads: BTreeMap<ByteString, Option<Position>>,
+    pub(crate) batch: WriteBatch,
+}
+
+impl Commit {
+    /// Fails with [`ActionKVError::Conflict`] if a key the transaction read
+    /// has been written since, going by where `current` has its value.
+    ///
+    /// Compaction moves values without changing them, so positions from
+    /// before it say nothing about whether a key was written since. Once the
+    /// store has been compacted, every key read that had a value counts as
+    /// changed, and a transaction that overlaps a compaction may fail with a
+    /// spurious conflict.
+    pub(crate) fn check(&self, current: &ActionKV) -> Result<()> {
+        let compacted = current.generation != self.generation;
+        for (key, seen) in &self.reads {
+            let now = current.live_position(key);
+            if now != *seen || (compacted && seen.is_some()) {
+                return Err(ActionKVError::Conflict { key: key.clone() });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ActionKVHandle;
+
+    #[test]
+    fn transactions_read_their_writes_and_commit_together() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        store.insert(b"from", b"10").unwrap();
+        store.insert(b"to", b"0").unwrap();
+
+        let moved = store
+            .transaction(|txn| {
+                assert_eq!(txn.get(b"from")?, Some(b"10".to_vec()));
+                txn.insert(b"from", b"7");
+                txn.insert(b"to", b"3");
+                txn.delete(b"pending");
+                assert_eq!(txn.get(b"from")?, Some(b"7".to_vec()));
+                assert_eq!(txn.get(b"pending")?, None);
+                Ok(3)
+            })
+            .unwrap();
+        assert_eq!(moved, 3);
+        assert_eq!(store.get(b"from").unwrap(), Some(b"7".to_vec()));
+        assert_eq!(store.get(b"to").unwrap(), Some(b"3".to_vec()));
+
+        let failed = store.transaction(|txn| {
+            txn.insert(b"from", b"0");
+            Err::<(), _>(ActionKVError::Remote("insufficient funds".to_string()))
+        });
+        assert!(matches!(failed, Err(ActionKVError::Remote(_))));
+        assert_eq!(store.get(b"from").unwrap(), Some(b"7".to_vec()));
+    }
+
+    #[test]
+    fn writes_to_keys_read_by_a_transaction_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        let handle = ActionKVHandle::new(store);
+        handle.insert(b"counter", b"1").unwrap();
+        handle.insert(b"other", b"1").unwrap();
+
+        let other = handle.clone();
+        let result = handle.transaction(|txn| {
+            txn.get(b"counter")?;
+            txn.insert(b"counter", b"2");
+            other.insert(b"counter", b"5")?;
+            Ok(())
+        });
+        assert!(matches!(
+            result,
+            Err(ActionKVError::Conflict { key }) if key == b"counter"
+        ));
+        assert_eq!(handle.get(b"counter").unwrap(), Some(b"5".to_vec()));
+
+        // Keys that were only written do not conflict.
+        handle
+            .transaction(|txn| {
+                assert_eq!(txn.get(b"other")?, Some(b"1".to_vec()));
+                txn.insert(b"counter", b"3");
+                other.insert(b"counter", b"4")?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(handle.get(b"counter").unwrap(), Some(b"3".to_vec()));
+
+        // Nor do keys that were only read once the write is in.
+        let result = handle.transaction(|txn| {
+            assert_eq!(txn.get(b"other")?, Some(b"1".to_vec()));
+            other.insert(b"other", b"2")?;
+            // A key reads the same every time within a transaction.
+            assert_eq!(txn.get(b"other")?, Some(b"1".to_vec()));
+            Ok(())
+        });
+        assert!(matches!(
+            result,
+            Err(ActionKVError::Conflict { key }) if key == b"other"
+        ));
+    }
+
+    #[test]
+    fn writes_moved_by_compaction_still_conflict() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = ActionKV::open(&dir.path().join("store.akv")).unwrap();
+        let handle = ActionKVHandle::new(store);
+        handle.insert(b"counter", b"1").unwrap();
+
+        let other = handle.clone();
+        let result = handle.transaction(|txn| {
+            assert_eq!(txn.get(b"counter")?, Some(b"1".to_vec()));
+            txn.insert(b"counter", b"2");
+            other.insert(b"counter", b"5")?;
+            other.compact()?;
+            Ok(())
+        });
+        assert!(matches!(
+            result,
+            Err(ActionKVError::Conflict { key }) if key == b"counter"
+        ));
+        assert_eq!(handle.get(b"counter").unwrap(), Some(b"5".to_vec()));
+
+        // Keys that were absent and still are do not conflict.
+        handle
+            .transaction(|txn| {
+                assert_eq!(txn.get(b"missing")?, None);
+                txn.insert(b"counter", b"6");
+                other.compact()?;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(handle.get(b"counter").unwrap(), Some(b"6".to_vec()));
+    }
+}
@@ -0,0 +1,282 @@
+//! Conversion of stores written by earlier versions.
+//!
+//! Before segments were added, a store was a single log file with its index
+//! file next to it. Three layouts of that file exist. The first records had
+//! no kind byte: `checksum | key_len | val_len | key | value`, with the
+//! checksum covering only the key and value, and deletes written as empty
+//! values. Later records are those of format version 1, at first without a
+//! file header in front of them, then with one of either format version.
+//!
+//! Whatever the layout, the live records are copied into a new store
+//! directory, which then takes the file's place.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File},
+    io::{self, BufReader, Read as _, Seek as _, SeekFrom},
+    path::Path,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt as _};
+
+use crate::{
+    file_header, now_millis, ActionKV, ActionKVError, ActionKVOptions, ByteString, Encoding,
+    KeyValuePair, LengthFormat, ReadAt, Recovery, Result, CRC32,
+};
+
+/// Size of a record header in the layout without a kind byte.
+const ORIGINAL_HEADER_LEN: usize = 12;
+
+/// Rewrites the store at `path` in the current format. See
+/// [`ActionKV::upgrade`].
+pub(crate) fn upgrade(path: &Path, options: &ActionKVOptions) -> Result<bool> {
+    if path.is_dir() {
+        // Segments are only ever written in the current format, which
+        // opening the store checks.
+        options.open(path)?;
+        return Ok(false);
+    }
+
+    let file = File::open(path)?;
+    let tmp_path = ActionKV::sibling_path(path, ".upgrade");
+    match fs::remove_dir_all(&tmp_path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+
+    let mut store = options.open(&tmp_path)?;
+    let copied = copy_log(&file, ActionKV::encoding(options), &mut store).and_then(|()| {
+        store.sync()?;
+        store.save_index()
+    });
+    drop(store);
+    if let Err(err) = copied {
+        let _ = fs::remove_dir_all(&tmp_path);
+        return Err(err);
+    }
+
+    // Moved aside rather than removed, so that a crash leaves it to be
+    // recovered by hand.
+    let old_path = ActionKV::sibling_path(path, ".old");
+    fs::rename(path, &old_path)?;
+    fs::rename(&tmp_path, path)?;
+    ActionKV::sync_parent_dir(path)?;
+    fs::remove_file(&old_path)?;
+    // Any index file holds offsets into the old log.
+    match fs::remove_file(ActionKV::sibling_path(path, ".index")) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+        _ => {}
+    }
+
+    Ok(true)
+}
+
+/// Copies the live records of the single log `file`, in whichever layout it
+/// is, to `store`.
+fn copy_log(file: &File, mut encoding: Encoding, store: &mut ActionKV) -> Result<()> {
+    if file_header::is_present(file)? {
+        let header = file_header::read(file, &encoding)?.expect("the file has a header");
+        encoding.lengths = header.lengths();
+        return copy_records(file, file_header::LEN, &encoding, store);
+    }
+
+    encoding.lengths = LengthFormat::Fixed32;
+    match copy_records(file, 0, &encoding, store) {
+        // A log whose very first record does not parse may be in the
+        // original layout.
+        Err(err) if err.is_corruption() && first_record_fails(&err) => {
+            let index = match read_original_index(file) {
+                Ok(index) => index,
+                Err(_) => return Err(err),
+            };
+            copy_original(file, &index, store)
+        }
+        copied => copied,
+    }
+}
+
+/// Copies the live records of a log whose records have kind bytes, the
+/// first of them at `start`, to `store`.
+fn copy_records(file: &File, start: u64, encoding: &Encoding, store: &mut ActionKV) -> Result<()> {
+    let mut index = BTreeMap::new();
+    let mut expiries = HashMap::new();
+    let mut hints = Vec::new();
+    // Read as if it were a segment, with an id no real segment has.
+    ActionKV::replay_segment(
+        file,
+        0,
+        start,
+        encoding,
+        &mut index,
+        &mut expiries,
+        &mut HashMap::new(),
+        &mut hints,
+        Recovery::None,
+    )?;
+
+    let now = now_millis();
+    for (key, position) in &index {
+        let expires_at = expiries.get(key).copied();
+        if expires_at.is_some_and(|expires_at| expires_at <= now) {
+            continue;
+        }
+        let kv = ActionKV::read_record_at(file, position.offset, encoding)?;
+        store.put(&kv.key, &kv.value, expires_at)?;
+    }
+    Ok(())
+}
+
+fn first_record_fails(err: &ActionKVError) -> bool {
+    matches!(
+        err,
+        ActionKVError::ChecksumMismatch { offset: 0, .. }
+            | ActionKVError::TruncatedRecord { offset: 0 }
+            | ActionKVError::OversizeRecord {
+                offset: Some(0),
+                ..
+            }
+            | ActionKVError::BadHeader { offset: 0, .. }
+            | ActionKVError::BadValue { offset: 0, .. }
+    )
+}
+
+/// Scans a log in the original layout, returning the position of the
+/// latest record of every key.
+fn read_original_index(file: &File) -> Result<BTreeMap<ByteString, u64>> {
+    let mut file = BufReader::new(file);
+    file.seek(SeekFrom::Start(0))?;
+    let mut index = BTreeMap::new();
+
+    loop {
+        let position = file.stream_position()?;
+        match read_original_record(&mut file, position)? {
+            Some(kv) => index.insert(kv.key, position),
+            None => break,
+        };
+    }
+
+    Ok(index)
+}
+
+fn read_original_record<R: io::Read>(file: &mut R, offset: u64) -> Result<Option<KeyValuePair>> {
+    let mut header = [0u8; ORIGINAL_HEADER_LEN];
+    match ActionKV::read_full(file, &mut header)? {
+        0 => return Ok(None),
+        n if n < header.len() => return Err(ActionKVError::TruncatedRecord { offset }),
+        _ => {}
+    }
+
+    let mut fields = &header[..];
+    let saved_checksum = fields.read_u32::<LittleEndian>()?;
+    let key_len = fields.read_u32::<LittleEndian>()?;
+    let val_len = fields.read_u32::<LittleEndian>()?;
+    let data_len = key_len as u64 + val_len as u64;
+
+    let mut data = ByteString::new();
+    file.take(data_len).read_to_end(&mut data)?;
+    if (data.len() as u64) < data_len {
+        return Err(ActionKVError::TruncatedRecord { offset });
+    }
+
+    let checksum = CRC32.checksum(&data);
+    if checksum != saved_checksum {
+        return Err(ActionKVError::ChecksumMismatch {
+            offset,
+            expected: saved_checksum,
+            actual: checksum,
+        });
+    }
+
+    let value = data.split_off(key_len as usize);
+    Ok(Some(KeyValuePair { key: data, value }))
+}
+
+/// Like `copy_records`, for a log in the original layout. Keys whose latest
+/// value is empty were deleted, and are left out.
+fn copy_original(
+    file: &File,
+    index: &BTreeMap<ByteString, u64>,
+    store: &mut ActionKV,
+) -> Result<()> {
+    for position in index.values() {
+        let mut buf = BufReader::new(ReadAt {
+            file,
+            offset: *position,
+        });
+        let kv = read_original_record(&mut buf, *position)?
+            .ok_or(ActionKVError::TruncatedRecord { offset: *position })?;
+        if !kv.value.is_empty() {
+            store.put(&kv.key, &kv.value, None)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{file_header::FileHeader, RecordKind};
+
+    fn original_record(key: &[u8], value: &[u8]) -> ByteString {
+        let data = [key, value].concat();
+        let mut record = CRC32.checksum(&data).to_le_bytes().to_vec();
+        record.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        record.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        record.extend_from_slice(&data);
+        record
+    }
+
+    #[test]
+    fn single_log_files_become_store_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let encoding = Encoding::default();
+        let mut log = FileHeader::new(&encoding).unwrap().encode().to_vec();
+        for (kind, key, value) in [
+            (RecordKind::Value, &b"a"[..], &b"1"[..]),
+            (RecordKind::Value, b"b", b"2"),
+            (RecordKind::Tombstone, b"a", b""),
+        ] {
+            ActionKV::write_record(&mut log, kind, key, value, None, &encoding).unwrap();
+        }
+        fs::write(&path, log).unwrap();
+        let index_path = ActionKV::sibling_path(&path, ".index");
+        fs::write(&index_path, b"stale").unwrap();
+
+        assert!(ActionKV::upgrade(&path).unwrap());
+        assert!(path.is_dir());
+        assert!(!index_path.exists());
+        assert!(!ActionKV::upgrade(&path).unwrap());
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 1);
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn original_layout_is_upgraded() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.akv");
+        let log = [
+            original_record(b"greet", b"hello!"),
+            original_record(b"byte", b"ciao!"),
+            original_record(b"gone", b"soon"),
+            original_record(b"greet", b"hi"),
+            original_record(b"gone", b""),
+        ]
+        .concat();
+        fs::write(&path, log).unwrap();
+
+        assert!(ActionKV::upgrade(&path).unwrap());
+        let mut store = ActionKV::open(&path).unwrap();
+        store.load().unwrap();
+        assert_eq!(store.index.len(), 2);
+        assert_eq!(store.get(b"greet").unwrap(), Some(b"hi".to_vec()));
+        assert_eq!(store.get(b"byte").unwrap(), Some(b"ciao!".to_vec()));
+
+        let garbage = dir.path().join("garbage.akv");
+        fs::write(&garbage, b"neither layout").unwrap();
+        assert!(ActionKV::upgrade(&garbage).is_err());
+        assert!(!ActionKV::sibling_path(&garbage, ".old").exists());
+    }
+}